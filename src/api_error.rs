@@ -0,0 +1,483 @@
+use gemini_code_assist_adapter::error::AdapterError;
+use gemini_rust::ClientError;
+use serde::Deserialize;
+use serde_json::Value;
+use std::{error::Error, fmt, time::Duration};
+
+/// Why a completion request failed.
+#[derive(Debug, Clone)]
+pub enum CompletionError {
+    /// The API answered with an error status.
+    Api(ApiError),
+    /// The request never got a response (DNS, TLS, connection reset…).
+    Network(String),
+    /// The client is not configured properly (missing key, not logged in…).
+    Config(String),
+    Other(String),
+}
+
+/// An error returned by the Gemini or Code Assist API, decoded from the
+/// `google.rpc.Status` body when there is one.
+#[derive(Debug, Clone, Default)]
+pub struct ApiError {
+    /// HTTP status code.
+    pub code: Option<u16>,
+    /// `google.rpc.Code` name, e.g. `RESOURCE_EXHAUSTED`.
+    pub status: Option<String>,
+    pub message: String,
+    /// Delay requested by the server through `RetryInfo`.
+    pub retry_delay: Option<Duration>,
+    pub quota_violations: Vec<QuotaViolation>,
+}
+
+/// One entry of the `QuotaFailure` details.
+#[derive(Debug, Clone, Default)]
+pub struct QuotaViolation {
+    pub metric: String,
+    pub quota_id: String,
+    pub value: Option<String>,
+}
+
+impl CompletionError {
+    /// Whether repeating the same request later may succeed.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Api(api) => api.is_transient(),
+            Self::Network(_) => true,
+            Self::Config(_) | Self::Other(_) => false,
+        }
+    }
+
+    /// Whether the quota of the API key ran out, so another key may still work.
+    pub fn is_quota_exhausted(&self) -> bool {
+        self.api().is_some_and(|api| match &api.status {
+            Some(status) => status == "RESOURCE_EXHAUSTED",
+            None => api.code == Some(429),
+        })
+    }
+
+    pub fn api(&self) -> Option<&ApiError> {
+        match self {
+            Self::Api(api) => Some(api),
+            _ => None,
+        }
+    }
+
+    /// Markdown explanation shown in the chat and in the error dialog.
+    pub fn user_message(&self) -> String {
+        match self {
+            Self::Api(api) => api.user_message(),
+            Self::Network(e) => format!("📡 **Network Error**\n\nCould not reach the API. Check your connection or proxy settings.\n\n**Details:** {e}"),
+            Self::Config(e) | Self::Other(e) => e.clone(),
+        }
+    }
+
+    /// Converts any error, e.g. one returned by `gemini_rust`.
+    pub fn from_error(error: impl Error + 'static) -> Self {
+        Self::from_dyn(&error)
+    }
+
+    fn from_dyn(error: &(dyn Error + 'static)) -> Self {
+        // Look for a typed error anywhere in the chain
+        let mut source = Some(error);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<AdapterError>() {
+                return Self::from_adapter(e);
+            }
+            if let Some(ClientError::BadResponse { code, description }) = e.downcast_ref() {
+                return Self::Api(ApiError::from_response(
+                    *code,
+                    description.as_deref().unwrap_or_default(),
+                ));
+            }
+            if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+                return Self::from_reqwest(e);
+            }
+            source = e.source();
+        }
+
+        let text = error.to_string();
+        match ApiError::scrape(&text) {
+            Some(api) => Self::Api(api),
+            None => Self::Other(text),
+        }
+    }
+
+    fn from_adapter(error: &AdapterError) -> Self {
+        match error {
+            AdapterError::ApiError { code, message } => {
+                Self::Api(ApiError::from_response(*code, message))
+            }
+            AdapterError::RequestFailed(e) => Self::from_reqwest(e),
+            AdapterError::StreamError(e) => Self::Network(e.clone()),
+            AdapterError::SerdeError(e) => Self::Other(format!("Invalid response: {e}")),
+        }
+    }
+
+    fn from_reqwest(error: &reqwest::Error) -> Self {
+        match error.status() {
+            Some(status) => Self::Api(ApiError {
+                code: Some(status.as_u16()),
+                message: error.to_string(),
+                ..Default::default()
+            }),
+            None => Self::Network(error.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for CompletionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Api(api) => write!(f, "{api}"),
+            Self::Network(e) => write!(f, "network error: {e}"),
+            Self::Config(e) | Self::Other(e) => f.write_str(e),
+        }
+    }
+}
+
+impl From<AdapterError> for CompletionError {
+    fn from(error: AdapterError) -> Self {
+        Self::from_adapter(&error)
+    }
+}
+
+impl From<Box<dyn Error + Send + Sync>> for CompletionError {
+    fn from(error: Box<dyn Error + Send + Sync>) -> Self {
+        Self::from_dyn(error.as_ref())
+    }
+}
+
+impl From<anyhow::Error> for CompletionError {
+    fn from(error: anyhow::Error) -> Self {
+        match error.downcast_ref::<AdapterError>() {
+            Some(e) => Self::from_adapter(e),
+            None => Self::Other(format!("{error:#}")),
+        }
+    }
+}
+
+/// `{"error": {...}}` body of an API error.
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: Status,
+}
+
+/// A `google.rpc.Status`.
+#[derive(Deserialize)]
+struct Status {
+    code: Option<u16>,
+    status: Option<String>,
+    #[serde(default)]
+    message: String,
+    /// Decoded one by one, so an unexpected detail doesn't hide the others.
+    #[serde(default)]
+    details: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "@type")]
+enum Detail {
+    #[serde(rename = "type.googleapis.com/google.rpc.RetryInfo")]
+    #[serde(rename_all = "camelCase")]
+    RetryInfo { retry_delay: Option<String> },
+    #[serde(rename = "type.googleapis.com/google.rpc.QuotaFailure")]
+    QuotaFailure {
+        #[serde(default)]
+        violations: Vec<Violation>,
+    },
+    #[serde(other)]
+    Other,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Violation {
+    #[serde(default)]
+    quota_metric: String,
+    #[serde(default)]
+    quota_id: String,
+    quota_value: Option<String>,
+}
+
+impl ApiError {
+    /// Decodes the body of an error response with the HTTP status `code`.
+    /// Bodies that are not a `google.rpc.Status` are kept as the message.
+    pub fn from_response(code: u16, body: &str) -> Self {
+        Self::from_body(body, Some(code)).unwrap_or_else(|| Self {
+            code: Some(code),
+            message: body.trim().to_string(),
+            ..Default::default()
+        })
+    }
+
+    /// Decodes a `{"error": {...}}` body. Streaming endpoints wrap it in an array.
+    fn from_body(body: &str, code: Option<u16>) -> Option<Self> {
+        let body = body.trim();
+        let status = serde_json::from_str::<ErrorBody>(body)
+            .or_else(|_| {
+                serde_json::from_str::<Vec<ErrorBody>>(body)?
+                    .into_iter()
+                    .next()
+                    .ok_or_else(|| serde::de::Error::custom("empty error array"))
+            })
+            .ok()?
+            .error;
+
+        let mut api = Self {
+            code: status.code.or(code),
+            status: status.status,
+            message: status.message,
+            ..Default::default()
+        };
+        for detail in status.details {
+            match Detail::deserialize(detail).unwrap_or(Detail::Other) {
+                Detail::RetryInfo { retry_delay } => {
+                    api.retry_delay = retry_delay.as_deref().and_then(parse_duration);
+                }
+                Detail::QuotaFailure { violations } => {
+                    api.quota_violations = violations
+                        .into_iter()
+                        .map(|v| QuotaViolation {
+                            metric: v.quota_metric,
+                            quota_id: v.quota_id,
+                            value: v.quota_value,
+                        })
+                        .collect();
+                }
+                Detail::Other => {}
+            }
+        }
+
+        // Last resort: some quota errors only mention the delay in the message
+        if api.retry_delay.is_none() {
+            if let Some(pos) = api.message.find("retry in ") {
+                api.retry_delay = parse_duration(&api.message[pos + "retry in ".len()..]);
+            }
+        }
+
+        Some(api)
+    }
+
+    /// Last resort for errors only available as text, e.g. from an error type that is
+    /// not matched in `CompletionError::from_dyn`: decodes the JSON object embedded in
+    /// the message, if any.
+    fn scrape(text: &str) -> Option<Self> {
+        let start = text.find('{')?;
+        let end = text.rfind('}')? + 1;
+        let body = text.get(start..end)?;
+        Self::from_body(body, None)
+            .or_else(|| Self::from_body(&body.replace("\\\"", "\"").replace("\\n", "\n"), None))
+    }
+
+    pub fn is_transient(&self) -> bool {
+        match self.status.as_deref() {
+            Some("RESOURCE_EXHAUSTED" | "UNAVAILABLE" | "INTERNAL" | "DEADLINE_EXCEEDED") => true,
+            Some(_) => false,
+            None => matches!(self.code, Some(429 | 500..=599)),
+        }
+    }
+
+    fn user_message(&self) -> String {
+        let code = self.code.map(|c| c.to_string()).unwrap_or_default();
+        match self.status.as_deref() {
+            Some("RESOURCE_EXHAUSTED") => {
+                let mut text = String::from("🛑 **Quota Exhausted (429)**\n\nYou've hit the Gemini API rate limit. Please wait a bit or check your Google AI Studio quota.");
+                for violation in &self.quota_violations {
+                    text.push_str(&format!("\n- `{}`", violation.quota_id));
+                    if let Some(value) = &violation.value {
+                        text.push_str(&format!(" (limit: {value})"));
+                    }
+                }
+                if let Some(delay) = self.retry_delay {
+                    text.push_str(&format!(
+                        "\n\n⏳ **Suggestion:** retry in {:.0}s",
+                        delay.as_secs_f32().ceil()
+                    ));
+                }
+                text
+            }
+            Some("NOT_FOUND") => format!("🚫 **Model Not Found (404)**\n\nThe model you selected is either not found or not supported for this operation. Try choosing a different model.\n\n**Details:** {}", self.message),
+            Some("PERMISSION_DENIED") => format!("🔒 **Permission Denied (403)**\n\nCheck your API Key and project permissions. Make sure the Key is valid for the selected region.\n\n**Details:** {}", self.message),
+            Some("INVALID_ARGUMENT") => format!("❌ **Invalid Request (400)**\n\nSomething is wrong with the request parameters.\n\n**Details:** {}", self.message),
+            Some(status) => format!("❗ **Gemini API Error ({code})**\n\n**Status:** {status}\n**Message:** {}", self.message),
+            None => format!("❗ **Gemini API Error ({code})**\n\n{}", self.message),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(code) = self.code {
+            write!(f, "{code} ")?;
+        }
+        if let Some(status) = &self.status {
+            write!(f, "{status}: ")?;
+        }
+        f.write_str(&self.message)
+    }
+}
+
+/// Parses protobuf-style durations such as `12s`, `1.5s` or `300ms`.
+/// Returns `None` for values a `Duration` can't hold.
+fn parse_duration(text: &str) -> Option<Duration> {
+    let text = text.trim_start();
+    let end = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let value: f64 = text[..end].parse().ok()?;
+    let secs = match &text[end..] {
+        unit if unit.starts_with("ms") => value / 1000.0,
+        unit if unit.starts_with('s') => value,
+        _ => return None,
+    };
+    Duration::try_from_secs_f64(secs).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUOTA_BODY: &str = r#"{
+  "error": {
+    "code": 429,
+    "message": "You exceeded your current quota, please check your plan and billing details.\n* Quota exceeded for metric: generativelanguage.googleapis.com/generate_content_free_tier_requests, limit: 10\nPlease retry in 38.233185578s.",
+    "status": "RESOURCE_EXHAUSTED",
+    "details": [
+      {
+        "@type": "type.googleapis.com/google.rpc.Help",
+        "links": [
+          {
+            "description": "Learn more about Gemini API quotas",
+            "url": "https://ai.google.dev/gemini-api/docs/rate-limits"
+          }
+        ]
+      },
+      {
+        "@type": "type.googleapis.com/google.rpc.QuotaFailure",
+        "violations": [
+          {
+            "quotaMetric": "generativelanguage.googleapis.com/generate_content_free_tier_requests",
+            "quotaId": "GenerateRequestsPerMinutePerProjectPerModel-FreeTier",
+            "quotaDimensions": {
+              "location": "global",
+              "model": "gemini-2.5-pro"
+            },
+            "quotaValue": "10"
+          }
+        ]
+      },
+      {
+        "@type": "type.googleapis.com/google.rpc.RetryInfo",
+        "retryDelay": "38s"
+      }
+    ]
+  }
+}"#;
+
+    #[test]
+    fn quota_body() {
+        let api = ApiError::from_response(429, QUOTA_BODY);
+        assert_eq!(api.code, Some(429));
+        assert_eq!(api.status.as_deref(), Some("RESOURCE_EXHAUSTED"));
+        assert_eq!(api.retry_delay, Some(Duration::from_secs(38)));
+        assert_eq!(api.quota_violations.len(), 1);
+        let violation = &api.quota_violations[0];
+        assert_eq!(
+            violation.quota_id,
+            "GenerateRequestsPerMinutePerProjectPerModel-FreeTier"
+        );
+        assert_eq!(
+            violation.metric,
+            "generativelanguage.googleapis.com/generate_content_free_tier_requests"
+        );
+        assert_eq!(violation.value.as_deref(), Some("10"));
+        assert!(api.is_transient());
+
+        let error = CompletionError::Api(api);
+        assert!(error.is_quota_exhausted());
+    }
+
+    #[test]
+    fn quota_body_in_stream_array() {
+        let body = format!("[{QUOTA_BODY}]");
+        let api = ApiError::from_response(429, &body);
+        assert_eq!(api.status.as_deref(), Some("RESOURCE_EXHAUSTED"));
+        assert_eq!(api.retry_delay, Some(Duration::from_secs(38)));
+    }
+
+    #[test]
+    fn retry_delay_from_message() {
+        let body = r#"{"error": {"code": 429, "message": "Resource exhausted. Please retry in 1.5s.", "status": "RESOURCE_EXHAUSTED"}}"#;
+        let api = ApiError::from_response(429, body);
+        assert_eq!(api.retry_delay, Some(Duration::from_millis(1500)));
+    }
+
+    #[test]
+    fn permission_denied_body() {
+        let body = r#"{
+  "error": {
+    "code": 403,
+    "message": "Method doesn't allow unregistered callers (callers without established identity). Please use API Key or other form of API consumer identity to call this API.",
+    "status": "PERMISSION_DENIED"
+  }
+}"#;
+        let api = ApiError::from_response(403, body);
+        assert_eq!(api.code, Some(403));
+        assert_eq!(api.status.as_deref(), Some("PERMISSION_DENIED"));
+        assert!(api.message.starts_with("Method doesn't allow"));
+        assert!(!api.is_transient());
+        assert!(!CompletionError::Api(api).is_quota_exhausted());
+    }
+
+    #[test]
+    fn not_found_body() {
+        let body = r#"{
+  "error": {
+    "code": 404,
+    "message": "models/gemini-foo is not found for API version v1beta, or is not supported for generateContent. Call ListModels to see the list of available models and their supported methods.",
+    "status": "NOT_FOUND"
+  }
+}"#;
+        let api = ApiError::from_response(404, body);
+        assert_eq!(api.code, Some(404));
+        assert_eq!(api.status.as_deref(), Some("NOT_FOUND"));
+        assert!(api.retry_delay.is_none());
+        assert!(api.quota_violations.is_empty());
+        assert!(!api.is_transient());
+    }
+
+    #[test]
+    fn non_json_body() {
+        let api = ApiError::from_response(502, "<html><body>502 Bad Gateway</body></html>\n");
+        assert_eq!(api.code, Some(502));
+        assert_eq!(api.status, None);
+        assert_eq!(api.message, "<html><body>502 Bad Gateway</body></html>");
+        assert!(api.is_transient());
+
+        let api = ApiError::from_response(429, "");
+        assert!(api.is_transient());
+        assert!(CompletionError::Api(api).is_quota_exhausted());
+    }
+
+    #[test]
+    fn scrape_escaped_body() {
+        let text = r#"bad response: "{\"error\": {\"code\": 404, \"message\": \"not found\", \"status\": \"NOT_FOUND\"}}""#;
+        let api = ApiError::scrape(text).unwrap();
+        assert_eq!(api.status.as_deref(), Some("NOT_FOUND"));
+        assert_eq!(api.message, "not found");
+        assert!(ApiError::scrape("connection reset by peer").is_none());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("12s"), Some(Duration::from_secs(12)));
+        assert_eq!(parse_duration(" 300ms"), Some(Duration::from_millis(300)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("12"), None);
+        assert_eq!(parse_duration("s"), None);
+        // Too large for a `Duration`, must not panic
+        assert_eq!(parse_duration(&format!("{}s", "9".repeat(400))), None);
+        assert_eq!(parse_duration(&format!("{}s", "9".repeat(30))), None);
+    }
+}
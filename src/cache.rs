@@ -0,0 +1,251 @@
+use crate::{
+    chat::{CompletionFlowerHandle, Message},
+    file_handler::Attachment,
+    widgets::{help, toggle, GeminiModel},
+};
+use anyhow::Result;
+use eframe::egui;
+use gemini_rust::{CacheExpirationRequest, Gemini, Tool};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::{LazyLock, Mutex, PoisonError},
+    time::Duration,
+};
+
+/// A cache that expires sooner than this is extended before being used.
+const REFRESH_MARGIN: Duration = Duration::from_secs(120);
+
+/// Hashes of the prefixes the API refused to cache. They are sent in full for the
+/// rest of the session, instead of failing again on every turn.
+static REFUSED: LazyLock<Mutex<HashSet<u64>>> = LazyLock::new(Mutex::default);
+
+fn refused() -> std::sync::MutexGuard<'static, HashSet<u64>> {
+    REFUSED.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Per-chat explicit context caching settings.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CachePolicy {
+    pub enabled: bool,
+    /// Lifetime of the cache, extended on every request.
+    pub ttl_minutes: u32,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_minutes: 60,
+        }
+    }
+}
+
+impl CachePolicy {
+    /// Lifetime of the cache, or `None` if caching is disabled.
+    pub fn ttl(&self) -> Option<Duration> {
+        self.enabled
+            .then(|| Duration::from_secs(u64::from(self.ttl_minutes.max(1)) * 60))
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.add(toggle(&mut self.enabled));
+            help(ui, "Upload the system prompt, tools and pinned attachments once and reuse them on every turn instead of sending them again. Pin an attachment by right-clicking it in the chat. Only works with the API key, and the cached part must be large enough (usually a few thousand tokens)", |ui| {
+                ui.label("Context caching");
+            });
+        });
+        ui.add_enabled_ui(self.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Keep for");
+                ui.add(egui::DragValue::new(&mut self.ttl_minutes).range(1..=1440));
+                ui.label("minutes");
+            });
+        });
+    }
+}
+
+/// A cached content resource holding the stable prefix of a chat.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CachedPrefix {
+    /// Resource name, `cachedContents/...`.
+    pub name: String,
+    /// Hash of the prefix the cache was created from.
+    pub prefix_hash: u64,
+    pub expires_at: chrono::DateTime<chrono::Utc>,
+    /// Number of pinned attachments in the cache.
+    pub files: usize,
+}
+
+impl CachedPrefix {
+    pub fn is_expired(&self) -> bool {
+        self.expires_at <= chrono::Utc::now()
+    }
+
+    fn expires_soon(&self) -> bool {
+        self.expires_at <= expiry(REFRESH_MARGIN)
+    }
+}
+
+/// The part of a request that stays the same across turns.
+pub struct Prefix<'a> {
+    model: GeminiModel,
+    system_prompt: Option<&'a str>,
+    tools: &'a [Tool],
+    files: Vec<Attachment>,
+}
+
+impl<'a> Prefix<'a> {
+    /// Collects the pinned attachments of the whole chat.
+    pub fn new(
+        model: GeminiModel,
+        system_prompt: Option<&'a str>,
+        tools: &'a [Tool],
+        messages: &[Message],
+    ) -> Self {
+        let files = messages
+            .iter()
+            .flat_map(|m| &m.files)
+            .filter(|f| f.pinned)
+            .cloned()
+            .collect();
+        Self {
+            model,
+            system_prompt,
+            tools,
+            files,
+        }
+    }
+
+    fn hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.model.to_string().hash(&mut hasher);
+        self.system_prompt.hash(&mut hasher);
+        serde_json::to_string(self.tools)
+            .unwrap_or_default()
+            .hash(&mut hasher);
+        for file in &self.files {
+            file.path.hash(&mut hasher);
+            // A file edited in place must not be served from the old cache
+            let modified = std::fs::metadata(&file.path).and_then(|m| m.modified());
+            modified.ok().hash(&mut hasher);
+        }
+        hasher.finish()
+    }
+
+    /// Rough size of the prefix, see [`crate::context::estimate_tokens`].
+    fn estimate_tokens(&self) -> u64 {
+        let text = self.system_prompt.map_or(0, str::len)
+            + serde_json::to_string(self.tools).map_or(0, |json| json.len());
+        text as u64 / crate::context::CHARS_PER_TOKEN
+            + self
+                .files
+                .iter()
+                .map(crate::context::estimate_file_tokens)
+                .sum::<u64>()
+    }
+
+    /// Whether creating a cache for this prefix can succeed.
+    fn is_cacheable(&self, prefix_hash: u64) -> bool {
+        let min_tokens = crate::models::catalogue()
+            .capabilities(self.model)
+            .min_cache_tokens;
+        let Some(min_tokens) = min_tokens else {
+            log::info!("{} doesn't support context caching", self.model);
+            return false;
+        };
+        if self.estimate_tokens() < u64::from(min_tokens) {
+            log::info!(
+                "the prefix is too small to be cached, at least {min_tokens} tokens are needed"
+            );
+            return false;
+        }
+        !refused().contains(&prefix_hash)
+    }
+
+    /// Returns a cache holding this prefix, reusing, extending or recreating `current`.
+    /// Returns `None` if the prefix can't be cached, it is then sent with the request.
+    pub async fn ensure_cache(
+        &self,
+        gemini: &Gemini,
+        ttl: Duration,
+        current: Option<&CachedPrefix>,
+        public_file_upload: bool,
+        status_channel: Option<(usize, &CompletionFlowerHandle)>,
+    ) -> Result<Option<CachedPrefix>> {
+        let prefix_hash = self.hash();
+
+        if let Some(cache) = current.filter(|c| c.prefix_hash == prefix_hash && !c.is_expired()) {
+            if !cache.expires_soon() {
+                return Ok(Some(cache.clone()));
+            }
+            match gemini
+                .get_cached_content(&cache.name)
+                .update(CacheExpirationRequest::from_ttl(ttl))
+                .await
+            {
+                Ok(_) => {
+                    log::info!("extended context cache {}", cache.name);
+                    return Ok(Some(CachedPrefix {
+                        expires_at: expiry(ttl),
+                        ..cache.clone()
+                    }));
+                }
+                Err(e) => log::info!("failed to extend context cache, recreating it: {e}"),
+            }
+        } else if let Some(cache) = current.filter(|c| !c.is_expired()) {
+            // The prefix changed, the old cache would only cost storage until it expires
+            if let Err(e) = gemini.get_cached_content(&cache.name).delete().await {
+                log::info!(
+                    "failed to delete outdated context cache {}: {e:?}",
+                    cache.name
+                );
+            }
+        }
+
+        if !self.is_cacheable(prefix_hash) {
+            return Ok(None);
+        }
+
+        let mut builder = gemini.create_cache().with_ttl(ttl);
+        if let Some(prompt) = self.system_prompt {
+            builder = builder.with_system_instruction(prompt);
+        }
+        for tool in self.tools {
+            builder = builder.with_tool(tool.clone());
+        }
+        if !self.files.is_empty() {
+            let message = Message::user(String::new(), self.model, self.files.clone());
+            let contents = crate::chat_completion::build_history(
+                gemini,
+                &[message],
+                None,
+                public_file_upload,
+                status_channel,
+            )
+            .await?;
+            builder = builder.with_contents(contents);
+        }
+
+        let handle = match builder.execute().await {
+            Ok(handle) => handle,
+            Err(e) => {
+                refused().insert(prefix_hash);
+                return Err(e.into());
+            }
+        };
+        log::info!("created context cache {}", handle.name());
+        Ok(Some(CachedPrefix {
+            name: handle.name().to_string(),
+            prefix_hash,
+            expires_at: expiry(ttl),
+            files: self.files.len(),
+        }))
+    }
+}
+
+fn expiry(ttl: Duration) -> chrono::DateTime<chrono::Utc> {
+    chrono::Utc::now() + chrono::Duration::from_std(ttl).unwrap_or_default()
+}
@@ -2,9 +2,16 @@
 use crate::sessions::SharedTts;
 
 use crate::{
+    api_error::CompletionError,
+    cache::CachedPrefix,
+    compare::Comparison,
+    context::{ContextMark, ContextPolicy},
     easymark::MemoizedEasymarkHighlighter,
-    file_handler::{Attachment, AttachmentState},
-    widgets::{self, GeminiModel, ModelPicker, Settings},
+    file_handler::{save_inline_data, Attachment, AttachmentState, FileAction},
+    prompts::{PromptTemplate, TemplateForm},
+    safety::SafetyRating,
+    tools::ToolScope,
+    widgets::{self, ApiKeyProfile, GeminiModel, ModelPicker, RetryPolicy, Settings},
 };
 use anyhow::{Context, Result};
 use eframe::egui::{
@@ -14,36 +21,18 @@ use egui_commonmark::{CommonMarkCache, CommonMarkViewer};
 use egui_modal::{Icon, Modal};
 use egui_robust_scroll::RobustVirtualScroll;
 use flowync::{error::Compact, CompactFlower, CompactHandle};
-use futures_util::TryStreamExt;
+use futures_util::{Stream, TryStreamExt};
 use gemini_rust::{
-    Gemini, GenerationConfig, HarmBlockThreshold, HarmCategory, Part, SafetySetting, UsageMetadata,
+    Candidate, CodeExecutionResult, Content, ExecutableCode, FunctionCall, FunctionResponse,
+    Gemini, GenerationConfig, GenerationResponse, Part, Role, SafetySetting, UsageMetadata,
 };
 use std::{
-    hash::{DefaultHasher, Hash, Hasher}, io::Write, path::PathBuf, sync::{
+    collections::VecDeque, hash::{DefaultHasher, Hash, Hasher}, io::Write, path::PathBuf, pin::Pin, sync::{
         Arc, atomic::{AtomicBool, Ordering}
     }, time::{Duration, Instant}
 };
 use tokio_stream::StreamExt;
 
-const SAFETY_SETTINGS: [SafetySetting; 4] = [
-    SafetySetting {
-        category: HarmCategory::Harassment,
-        threshold: HarmBlockThreshold::BlockNone,
-    },
-    SafetySetting {
-        category: HarmCategory::HateSpeech,
-        threshold: HarmBlockThreshold::BlockNone,
-    },
-    SafetySetting {
-        category: HarmCategory::SexuallyExplicit,
-        threshold: HarmBlockThreshold::BlockNone,
-    },
-    SafetySetting {
-        category: HarmCategory::DangerousContent,
-        threshold: HarmBlockThreshold::BlockNone,
-    },
-];
-
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum MessageRole {
     User,
@@ -69,10 +58,180 @@ pub struct Message {
     pub is_speaking: bool,
     pub files: Vec<Attachment>,
     pub is_prepending: bool,
+    #[serde(skip)]
+    pub is_editing: bool,
     pub is_thought: bool,
     pub usage: Option<UsageMetadata>,
     #[serde(skip)]
     pub status_message: Option<String>,
+    pub tool_call: Option<ToolCall>,
+    pub code_execution: Option<CodeExecution>,
+    /// Web pages the response was grounded on, shown as footnotes.
+    pub sources: Vec<Source>,
+    /// Parts of the response as received, with thought flags and signatures.
+    pub parts: Vec<Part>,
+    /// Texts of all the candidates of the response, if several were requested.
+    /// `content` holds the selected one, which is the one sent in the history.
+    pub candidates: Vec<String>,
+    pub candidate_index: usize,
+    /// Why the model stopped generating, e.g. `STOP` or `MAX_TOKENS`.
+    pub finish_reason: Option<String>,
+    pub safety_ratings: Vec<SafetyRating>,
+    /// Problems found when checking a structured response against its schema.
+    /// `None` if structured output was disabled.
+    pub schema_errors: Option<Vec<String>>,
+    #[serde(skip)]
+    pub parsed_json: Option<serde_json::Value>,
+    /// Whether the message is sent with the next requests, see [`ContextPolicy`].
+    pub context: ContextMark,
+    /// Summary of the conversation up to this message, sent in place of it.
+    pub summary: Option<String>,
+    /// Inactive versions of the conversation starting at this message.
+    pub alternatives: Vec<Vec<Message>>,
+    /// Position of the active version among all versions of this message.
+    pub branch_index: usize,
+}
+
+/// A function call made by the model, together with the locally computed result.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub args: serde_json::Value,
+    pub response: Option<serde_json::Value>,
+    /// Set while the call waits for the user to allow it.
+    #[serde(skip)]
+    pub approval: Option<ToolApproval>,
+}
+
+/// Answer to a tool call that has to be allowed by the user, awaited by the request.
+#[derive(Clone)]
+pub struct ToolApproval(Arc<std::sync::Mutex<Option<tokio::sync::oneshot::Sender<bool>>>>);
+
+impl ToolApproval {
+    fn new() -> (Self, tokio::sync::oneshot::Receiver<bool>) {
+        let (sender, receiver) = tokio::sync::oneshot::channel();
+        (
+            Self(Arc::new(std::sync::Mutex::new(Some(sender)))),
+            receiver,
+        )
+    }
+
+    /// Whether the user hasn't answered yet.
+    pub fn is_pending(&self) -> bool {
+        self.0.lock().is_ok_and(|sender| sender.is_some())
+    }
+
+    pub fn answer(&self, allow: bool) {
+        if let Some(sender) = self.0.lock().ok().and_then(|mut sender| sender.take()) {
+            let _ = sender.send(allow);
+        }
+    }
+}
+
+impl std::fmt::Debug for ToolApproval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ToolApproval")
+            .field("pending", &self.is_pending())
+            .finish()
+    }
+}
+
+impl ToolCall {
+    pub fn call_part(&self) -> Part {
+        Part::FunctionCall {
+            function_call: FunctionCall::new(self.name.clone(), self.args.clone()),
+            thought_signature: None,
+        }
+    }
+
+    pub fn response_part(&self) -> Option<Part> {
+        self.response.clone().map(|response| Part::FunctionResponse {
+            function_response: FunctionResponse::new(self.name.clone(), response),
+        })
+    }
+}
+
+/// Code the model ran with the code execution tool, and its result.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CodeExecution {
+    pub language: String,
+    pub code: String,
+    pub outcome: Option<String>,
+    pub output: Option<String>,
+}
+
+impl CodeExecution {
+    fn from_part(executable_code: &ExecutableCode) -> Self {
+        Self {
+            language: crate::safety::api_name(&executable_code.language).to_lowercase(),
+            code: executable_code.code.clone(),
+            outcome: None,
+            output: None,
+        }
+    }
+
+    fn set_result(&mut self, result: &CodeExecutionResult) {
+        self.outcome = Some(crate::safety::api_name(&result.outcome));
+        self.output = Some(result.output.clone().unwrap_or_default());
+    }
+
+    /// Whether the code ran without errors (or hasn't finished yet).
+    fn succeeded(&self) -> bool {
+        self.outcome.as_deref().is_none_or(|o| o == "OUTCOME_OK")
+    }
+
+    /// Markdown rendering of the code and its output, also used as history context.
+    pub fn to_markdown(&self) -> String {
+        let mut text = format!("```{}\n{}\n```", self.language, self.code.trim_end());
+        if let Some(output) = &self.output {
+            text.push_str(&format!("\n\nOutput:\n```\n{}\n```", output.trim_end()));
+        }
+        text
+    }
+}
+
+/// A web page cited by a grounded response.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Source {
+    pub title: String,
+    pub uri: String,
+}
+
+impl Source {
+    /// Collects the grounding chunks, citations and retrieved URLs of a response candidate.
+    fn from_candidate(candidate: &Candidate) -> Vec<Self> {
+        let mut sources: Vec<Self> = Vec::new();
+        let grounding = candidate
+            .grounding_metadata
+            .iter()
+            .flat_map(|metadata| metadata.grounding_chunks.iter().flatten())
+            .filter_map(|chunk| chunk.web.as_ref())
+            .map(|web| (web.uri.clone(), web.title.clone()));
+        let citations = candidate
+            .citation_metadata
+            .iter()
+            .flat_map(|metadata| &metadata.citation_sources)
+            .map(|citation| (citation.uri.clone(), citation.title.clone()));
+        let urls = candidate
+            .url_context_metadata
+            .iter()
+            .flat_map(|metadata| &metadata.url_metadata)
+            .map(|url| (url.retrieved_url.clone(), None));
+
+        for (uri, title) in grounding.chain(citations).chain(urls) {
+            let Some(uri) = uri.filter(|uri| !uri.is_empty()) else {
+                continue;
+            };
+            if sources.iter().any(|s| s.uri == uri) {
+                continue;
+            }
+            sources.push(Self {
+                title: title.unwrap_or_else(|| uri.clone()),
+                uri,
+            });
+        }
+        sources
+    }
 }
 
 impl Default for Message {
@@ -89,10 +248,25 @@ impl Default for Message {
             model: GeminiModel::default(),
             files: Vec::new(),
             is_prepending: false,
+            is_editing: false,
             is_thought: false,
             generation_time: None,
             usage: None,
             status_message: None,
+            tool_call: None,
+            code_execution: None,
+            sources: Vec::new(),
+            parts: Vec::new(),
+            candidates: Vec::new(),
+            candidate_index: 0,
+            finish_reason: None,
+            safety_ratings: Vec::new(),
+            schema_errors: None,
+            parsed_json: None,
+            context: ContextMark::Included,
+            summary: None,
+            alternatives: Vec::new(),
+            branch_index: 0,
         }
     }
 }
@@ -134,12 +308,18 @@ enum MessageAction {
     None,
     Retry(usize),
     Regenerate(usize),
+    Edit(usize),
+    Resend { idx: usize, truncate: bool },
     Delete(usize),
+    SwitchBranch { idx: usize, branch: usize },
+    UseAsInput(Attachment),
+    Continue(usize),
+    SelectCandidate { idx: usize, candidate: usize },
 }
 
 impl Message {
     #[inline]
-    fn user(content: String, model: GeminiModel, files: Vec<Attachment>) -> Self {
+    pub fn user(content: String, model: GeminiModel, files: Vec<Attachment>) -> Self {
         Self {
             content,
             role: MessageRole::User,
@@ -151,7 +331,7 @@ impl Message {
     }
 
     #[inline]
-    fn assistant(content: String, model: GeminiModel) -> Self {
+    pub fn assistant(content: String, model: GeminiModel) -> Self {
         Self {
             content,
             role: MessageRole::Assistant,
@@ -161,23 +341,372 @@ impl Message {
         }
     }
 
+    #[inline]
+    fn tool(call: ToolCall, model: GeminiModel) -> Self {
+        Self {
+            role: MessageRole::Assistant,
+            is_generating: true,
+            model,
+            tool_call: Some(call),
+            ..Default::default()
+        }
+    }
+
     #[inline]
     const fn is_user(&self) -> bool {
         matches!(self.role, MessageRole::User)
     }
 
-    fn show(
+    #[inline]
+    fn code_execution(execution: CodeExecution, model: GeminiModel) -> Self {
+        Self {
+            role: MessageRole::Assistant,
+            is_generating: true,
+            model,
+            code_execution: Some(execution),
+            ..Default::default()
+        }
+    }
+
+    /// Whether this message is a thought, tool call or code block rather than an answer.
+    #[inline]
+    fn is_block(&self) -> bool {
+        self.is_thought || self.tool_call.is_some() || self.code_execution.is_some()
+    }
+
+    /// Whether this is the empty assistant message created before the response arrives.
+    #[inline]
+    fn is_placeholder(&self) -> bool {
+        !self.is_user() && !self.is_block() && self.content.is_empty()
+    }
+
+    /// Parts to send back for this message, if they still match its content.
+    /// Edited, prefilled or switched responses are sent as plain text instead.
+    pub fn raw_parts(&self) -> Option<&[Part]> {
+        if self.parts.is_empty() {
+            return None;
+        }
+        let text: String = self
+            .parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::Text { text, thought, .. } if thought.unwrap_or(false) == self.is_thought => {
+                    Some(text.as_str())
+                }
+                _ => None,
+            })
+            .collect();
+        (text == self.content).then_some(self.parts.as_slice())
+    }
+
+    /// Drops the text of thoughts, keeping the parts that carry a thought signature.
+    fn strip_thought_text(&mut self) {
+        self.content.clear();
+        self.parts.retain_mut(|part| match part {
+            Part::Text {
+                text,
+                thought_signature,
+                ..
+            } => {
+                text.clear();
+                thought_signature.is_some()
+            }
+            _ => true,
+        });
+    }
+
+    /// Stops the spinner of a response message that is followed by another one.
+    fn finish_generating(&mut self) {
+        self.is_generating = false;
+        self.generation_time = Some(self.requested_at.elapsed());
+    }
+
+    /// Number of versions of this message, including the active one.
+    #[inline]
+    fn branch_count(&self) -> usize {
+        self.alternatives.len() + 1
+    }
+
+    fn show_branch_switcher(&self, ui: &mut egui::Ui, idx: usize) -> MessageAction {
+        let mut action = MessageAction::None;
+        let count = self.branch_count();
+        let current = self.branch_index.min(count - 1);
+
+        ui.spacing_mut().item_spacing.x = 2.0;
+        if ui
+            .add_enabled(current > 0, egui::Button::new("◀").small().frame(false))
+            .on_hover_text("Previous version")
+            .clicked()
+        {
+            action = MessageAction::SwitchBranch {
+                idx,
+                branch: current - 1,
+            };
+        }
+        ui.weak(format!("{}/{}", current + 1, count));
+        if ui
+            .add_enabled(current + 1 < count, egui::Button::new("▶").small().frame(false))
+            .on_hover_text("Next version")
+            .clicked()
+        {
+            action = MessageAction::SwitchBranch {
+                idx,
+                branch: current + 1,
+            };
+        }
+        action
+    }
+
+    fn show_candidate_tabs(&self, ui: &mut egui::Ui, idx: usize) -> MessageAction {
+        let mut action = MessageAction::None;
+        ui.spacing_mut().item_spacing.x = 2.0;
+        for candidate in 0..self.candidates.len() {
+            if ui
+                .selectable_label(
+                    candidate == self.candidate_index,
+                    format!("#{}", candidate + 1),
+                )
+                .on_hover_text("Candidate response, the selected one is kept in the history")
+                .clicked()
+                && candidate != self.candidate_index
+            {
+                action = MessageAction::SelectCandidate { idx, candidate };
+            }
+        }
+        action
+    }
+
+    /// Makes another candidate the content of the message.
+    fn select_candidate(&mut self, candidate: usize) {
+        if candidate >= self.candidates.len() {
+            return;
+        }
+        // Keep edits made to the current candidate
+        self.candidates[self.candidate_index.min(self.candidates.len() - 1)] =
+            std::mem::take(&mut self.content);
+        self.content = self.candidates[candidate].clone();
+        self.candidate_index = candidate;
+        self.parsed_json = None;
+    }
+
+    fn show_code_execution(&self, ui: &mut egui::Ui, commonmark_cache: &mut CommonMarkCache) {
+        let Some(execution) = &self.code_execution else {
+            return;
+        };
+
+        ui.vertical(|ui| {
+            CommonMarkViewer::new().show(
+                ui,
+                commonmark_cache,
+                &format!("```{}\n{}\n```", execution.language, execution.code.trim_end()),
+            );
+
+            match &execution.output {
+                Some(output) => {
+                    ui.horizontal(|ui| {
+                        if execution.succeeded() {
+                            ui.weak("▶ Output");
+                        } else {
+                            ui.colored_label(
+                                ui.visuals().error_fg_color,
+                                format!("⚠ {}", execution.outcome.as_deref().unwrap_or("Failed")),
+                            );
+                        }
+                    });
+                    CommonMarkViewer::new().show(
+                        ui,
+                        commonmark_cache,
+                        &format!("```\n{}\n```", output.trim_end()),
+                    );
+                }
+                None => {
+                    ui.horizontal(|ui| {
+                        ui.spinner();
+                        ui.label("Running code…");
+                    });
+                }
+            }
+        });
+    }
+
+    /// Explains a response that was blocked or cut off, with the notable safety ratings.
+    /// Returns `true` if the user asked to continue a truncated response.
+    fn show_finish_reason(
+        &self,
+        ui: &mut egui::Ui,
+        message_offset: f32,
+        can_continue: bool,
+    ) -> bool {
+        let reason = self
+            .finish_reason
+            .as_deref()
+            .and_then(crate::safety::describe_finish_reason);
+        let ratings: Vec<String> = self
+            .safety_ratings
+            .iter()
+            .filter(|r| r.is_notable())
+            .map(SafetyRating::describe)
+            .collect();
+        if reason.is_none() && ratings.is_empty() {
+            return false;
+        }
+
+        let mut continue_clicked = false;
+        ui.horizontal(|ui| {
+            ui.add_space(message_offset);
+            let label = match reason {
+                Some(reason) => ui.colored_label(ui.visuals().warn_fg_color, reason),
+                None => ui.weak("🛡 Safety ratings"),
+            };
+            if !ratings.is_empty() {
+                label.on_hover_text(ratings.join("\n"));
+            }
+            if can_continue
+                && self.finish_reason.as_deref() == Some("MAX_TOKENS")
+                && !self.is_block()
+            {
+                continue_clicked = ui
+                    .button("⏩ Continue")
+                    .on_hover_text("Ask the model to go on from where the response was cut off")
+                    .clicked();
+            }
+        });
+        ui.add_space(4.0);
+        continue_clicked
+    }
+
+    fn show_structured_output(&mut self, ui: &mut egui::Ui, idx: usize) {
+        let Some(errors) = &self.schema_errors else {
+            return;
+        };
+
+        if errors.is_empty() {
+            ui.colored_label(
+                egui::Color32::from_rgb(0x4c, 0xaf, 0x50),
+                "✔ Matches schema",
+            );
+        } else {
+            ui.colored_label(
+                ui.visuals().error_fg_color,
+                format!("⚠ {} schema error(s)", errors.len()),
+            )
+            .on_hover_text(errors.join("\n"));
+        }
+
+        if self.parsed_json.is_none() {
+            self.parsed_json = crate::structured::parse_json(&self.content).ok();
+        }
+        if let Some(value) = &self.parsed_json {
+            if value.is_object() || value.is_array() {
+                egui::CollapsingHeader::new("JSON tree")
+                    .id_salt(("json_tree", idx))
+                    .show(ui, |ui| {
+                        crate::structured::show_json_tree(
+                            ui,
+                            "$",
+                            value,
+                            egui::Id::new(("json", idx)),
+                        );
+                    });
+            }
+        }
+    }
+
+    fn show_tool_call(&self, ui: &mut egui::Ui, commonmark_cache: &mut CommonMarkCache) {
+        let Some(call) = &self.tool_call else {
+            return;
+        };
+
+        Frame::group(ui.style())
+            .inner_margin(Margin::symmetric(8, 4))
+            .show(ui, |ui| {
+                let pending = call.approval.as_ref().filter(|a| a.is_pending());
+                if let Some(approval) = pending {
+                    ui.horizontal(|ui| {
+                        ui.label(format!("🔧 Allow `{}` to run?", call.name));
+                        if ui.button("Allow").clicked() {
+                            approval.answer(true);
+                        }
+                        if ui.button("Deny").clicked() {
+                            approval.answer(false);
+                        }
+                    });
+                }
+                egui::CollapsingHeader::new(format!("🔧 Tool call: {}", call.name))
+                    .id_salt(self.time.timestamp_millis())
+                    .default_open(false)
+                    .open(pending.is_some().then_some(true))
+                    .show(ui, |ui| {
+                        ui.weak("Arguments");
+                        let args = serde_json::to_string_pretty(&call.args).unwrap_or_default();
+                        CommonMarkViewer::new().show(
+                            ui,
+                            commonmark_cache,
+                            &format!("```json\n{args}\n```"),
+                        );
+
+                        ui.weak("Result");
+                        if let Some(response) = &call.response {
+                            let response =
+                                serde_json::to_string_pretty(response).unwrap_or_default();
+                            CommonMarkViewer::new().show(
+                                ui,
+                                commonmark_cache,
+                                &format!("```json\n{response}\n```"),
+                            );
+                        } else {
+                            ui.horizontal(|ui| {
+                                ui.spinner();
+                                ui.label("Running…");
+                            });
+                        }
+                    });
+            });
+    }
+
+    /// Tells whether the message is left out of the requests by the context policy.
+    fn show_context_mark(&self, ui: &mut egui::Ui) {
+        match self.context {
+            ContextMark::Included => {}
+            ContextMark::Excluded => {
+                ui.weak("⊘ not in context")
+                    .on_hover_text("Left out of the requests to fit the context window");
+            }
+            ContextMark::FilesExcluded => {
+                ui.weak("📎 attachments not sent").on_hover_text(
+                    "The attachments are left out of the requests to fit the context window",
+                );
+            }
+            ContextMark::Summarized => {
+                let label = ui.weak("∑ summarized");
+                match &self.summary {
+                    Some(summary) => label.on_hover_text(summary),
+                    None => {
+                        label.on_hover_text("Will be replaced by a summary in the next request")
+                    }
+                };
+            }
+        }
+    }
+
+    /// With `read_only`, the buttons that change the conversation are hidden,
+    /// e.g. in a comparison column.
+    pub fn show(
         &mut self,
         ui: &mut egui::Ui,
         commonmark_cache: &mut CommonMarkCache,
         #[cfg(feature = "tts")] tts: SharedTts,
         idx: usize,
+        read_only: bool,
         prepend_buf: &mut String,
+        edit_files: &mut Vec<Attachment>,
     ) -> MessageAction {
+        let mut action = MessageAction::None;
+
         // message role
         let message_offset = ui
             .horizontal(|ui| {
-                if self.is_user() {
+                let offset = if self.is_user() {
                     let f = ui.label("👤").rect.left();
                     ui.label("You").rect.left() - f
                 } else {
@@ -204,20 +733,35 @@ impl Message {
                         ui.weak(format!("{} ᵗ", total)).on_hover_text(text);
                     }
                     offset
+                };
+
+                self.show_context_mark(ui);
+                if self.candidates.len() > 1 && !self.is_generating && !read_only {
+                    action = self.show_candidate_tabs(ui, idx);
                 }
+                if !self.alternatives.is_empty() && !read_only {
+                    action = self.show_branch_switcher(ui, idx);
+                }
+                offset
             })
             .inner;
 
-        let is_commonmark = !self.content.is_empty() && !self.is_error && !self.is_prepending;
+        let is_commonmark = !self.content.is_empty()
+            && !self.is_error
+            && !self.is_prepending
+            && !self.is_editing;
         if is_commonmark && !self.is_thought {
             ui.add_space(-TextStyle::Body.resolve(ui.style()).size + 4.0);
         }
 
         // message content / spinner
-        let mut action = MessageAction::None;
         ui.horizontal(|ui| {
             ui.add_space(message_offset);
-            if self.content.is_empty() && self.is_generating && !self.is_error {
+            if self.tool_call.is_some() {
+                self.show_tool_call(ui, commonmark_cache);
+            } else if self.code_execution.is_some() {
+                self.show_code_execution(ui, commonmark_cache);
+            } else if self.content.is_empty() && self.is_generating && !self.is_error {
                 ui.horizontal(|ui| {
                     ui.add(egui::Spinner::new());
 
@@ -238,16 +782,68 @@ impl Message {
                 ui.vertical(|ui| {
                     CommonMarkViewer::new().show(ui, commonmark_cache, &self.content);
                     ui.add_space(8.0);
-                    if ui
-                        .button("🔄 Retry Generation")
-                        .on_hover_text(
-                            "Try to generate a response again. Make sure you have a valid API Key and stable connection.",
-                        )
-                        .clicked()
+                    if !read_only
+                        && ui
+                            .button("🔄 Retry Generation")
+                            .on_hover_text(
+                                "Try to generate a response again. Make sure you have a valid API Key and stable connection.",
+                            )
+                            .clicked()
                     {
                         action = MessageAction::Retry(idx);
                     }
                 });
+            } else if self.is_editing {
+                ui.vertical(|ui| {
+                    let textedit = ui.add(
+                        egui::TextEdit::multiline(prepend_buf)
+                            .hint_text("Edit your message…")
+                            .desired_width(f32::INFINITY),
+                    );
+                    let cancel = textedit.lost_focus() && ui.input(|i| i.key_pressed(Key::Escape));
+
+                    if !edit_files.is_empty() {
+                        egui::ScrollArea::horizontal()
+                            .id_salt((idx, "edit_files"))
+                            .show(ui, |ui| {
+                                ui.horizontal(|ui| {
+                                    crate::file_handler::show_files(ui, edit_files, true);
+                                });
+                            });
+                    }
+                    ui.weak("Drop files or use ➕ to attach more.");
+
+                    ui.horizontal(|ui| {
+                        if ui
+                            .button("🔀 Send as new version")
+                            .on_hover_text(
+                                "Keep the current conversation as another version \
+                                and continue from the edited message",
+                            )
+                            .clicked()
+                        {
+                            action = MessageAction::Resend {
+                                idx,
+                                truncate: false,
+                            };
+                        }
+                        if ui
+                            .button("✂ Send and discard the rest")
+                            .on_hover_text("Remove every message after this one and continue from the edited message")
+                            .clicked()
+                        {
+                            action = MessageAction::Resend {
+                                idx,
+                                truncate: true,
+                            };
+                        }
+                        if cancel || ui.button("❌ Cancel").clicked() {
+                            self.is_editing = false;
+                            prepend_buf.clear();
+                            edit_files.clear();
+                        }
+                    });
+                });
             } else if self.is_prepending {
                 let textedit = ui.add(
                     egui::TextEdit::multiline(prepend_buf).hint_text("Prepend text to response…"),
@@ -270,9 +866,7 @@ impl Message {
                         )
                         .clicked()
                     {
-                        self.content = prepend_buf.clone();
                         self.is_prepending = false;
-                        self.is_generating = true;
                         action = MessageAction::Regenerate(idx);
                     }
                     if !prepend_buf.is_empty()
@@ -283,8 +877,8 @@ impl Message {
                             )
                             .clicked()
                     {
-                        self.content = prepend_buf.clone();
-                        cancel_prepend!();
+                        self.is_prepending = false;
+                        action = MessageAction::Edit(idx);
                     }
                     if ui.button("❌ Cancel").clicked() {
                         cancel_prepend!();
@@ -330,7 +924,7 @@ impl Message {
         });
 
         // files
-        if !self.files.is_empty() {
+        if !self.files.is_empty() && !self.is_editing {
             if is_commonmark {
                 ui.add_space(4.0);
             }
@@ -338,14 +932,53 @@ impl Message {
                 ui.add_space(message_offset);
                 egui::ScrollArea::horizontal().id_salt(idx).show(ui, |ui| {
                     ui.horizontal(|ui| {
-                        crate::file_handler::show_files(ui, &mut self.files, false);
+                        if let Some(FileAction::UseAsInput(attachment)) =
+                            crate::file_handler::show_files(ui, &mut self.files, false)
+                                .filter(|_| !read_only)
+                        {
+                            action = MessageAction::UseAsInput(attachment);
+                        }
                     });
                 })
             });
             ui.add_space(8.0);
         }
 
-        if self.is_prepending {
+        // structured output
+        if self.schema_errors.is_some() && !self.is_generating && !self.is_editing {
+            ui.horizontal(|ui| {
+                ui.add_space(message_offset);
+                ui.vertical(|ui| self.show_structured_output(ui, idx));
+            });
+            ui.add_space(4.0);
+        }
+
+        // grounding sources
+        if !self.sources.is_empty() && !self.is_editing {
+            ui.horizontal(|ui| {
+                ui.add_space(message_offset);
+                ui.vertical(|ui| {
+                    ui.weak("Sources");
+                    for (i, source) in self.sources.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            ui.weak(format!("[{}]", i + 1));
+                            ui.hyperlink_to(&source.title, &source.uri)
+                                .on_hover_text(&source.uri);
+                        });
+                    }
+                });
+            });
+            ui.add_space(4.0);
+        }
+
+        if !self.is_generating
+            && !self.is_editing
+            && self.show_finish_reason(ui, message_offset, !read_only)
+        {
+            action = MessageAction::Continue(idx);
+        }
+
+        if self.is_prepending || self.is_editing {
             return action;
         }
 
@@ -399,6 +1032,10 @@ impl Message {
                     }
                 }
 
+                if read_only {
+                    return;
+                }
+
                 if ui
                     .add(
                         egui::Button::new("🗑")
@@ -411,20 +1048,36 @@ impl Message {
                     action = MessageAction::Delete(idx);
                 }
 
-                if !self.is_user()
-                    && !self.is_thought
+                if self.is_user()
                     && prepend_buf.is_empty()
                     && ui
                         .add(
-                            egui::Button::new("🔄")
+                            egui::Button::new("\u{270f}")
                                 .small()
                                 .fill(egui::Color32::TRANSPARENT),
                         )
-                        .on_hover_text("Regenerate")
+                        .on_hover_text("Edit and resend")
                         .clicked()
                 {
-                    prepend_buf.clear();
-                    self.is_prepending = true;
+                    *prepend_buf = self.content.clone();
+                    *edit_files = self.files.clone();
+                    self.is_editing = true;
+                }
+
+                if !self.is_user()
+                    && !self.is_block()
+                    && prepend_buf.is_empty()
+                    && ui
+                        .add(
+                            egui::Button::new("🔄")
+                                .small()
+                                .fill(egui::Color32::TRANSPARENT),
+                        )
+                        .on_hover_text("Regenerate")
+                        .clicked()
+                {
+                    prepend_buf.clear();
+                    self.is_prepending = true;
                 }
             });
         }
@@ -456,12 +1109,57 @@ pub enum ChatProgress {
         path: PathBuf,
         file: gemini_rust::File,
     },
+    ToolResult {
+        name: String,
+        response: serde_json::Value,
+    },
+    // A tool call waits for the user to allow it
+    ToolApproval {
+        name: String,
+        approval: ToolApproval,
+    },
+    // Inline data (e.g. a generated image) saved to disk
+    GeneratedFile {
+        attachment: Attachment,
+    },
+    // Grounding or citation sources of the response
+    Sources {
+        sources: Vec<Source>,
+    },
+    // Answers of the other candidates, when several were requested
+    Candidates {
+        texts: Vec<String>,
+    },
+    // Finish reason and safety ratings of the response
+    Finish {
+        finish_reason: Option<String>,
+        safety_ratings: Vec<SafetyRating>,
+    },
+    // Summary of the older messages, generated before the request
+    ContextSummary {
+        idx: usize,
+        summary: String,
+    },
+    // Context cache created or extended for the request
+    ContextCache {
+        cache: CachedPrefix,
+    },
+    // The request moved to another API key after the quota of the chat's key ran out
+    ApiKey {
+        name: String,
+    },
 }
 
-pub type CompletionFlower =
-    CompactFlower<(usize, ChatProgress), (usize, String, Option<UsageMetadata>), (usize, String)>;
-pub type CompletionFlowerHandle =
-    CompactHandle<(usize, ChatProgress), (usize, String, Option<UsageMetadata>), (usize, String)>;
+pub type CompletionFlower = CompactFlower<
+    (usize, ChatProgress),
+    (usize, String, Option<UsageMetadata>),
+    (usize, CompletionError),
+>;
+pub type CompletionFlowerHandle = CompactHandle<
+    (usize, ChatProgress),
+    (usize, String, Option<UsageMetadata>),
+    (usize, CompletionError),
+>;
 
 #[derive(serde::Deserialize, serde::Serialize)]
 #[serde(default)]
@@ -473,6 +1171,8 @@ pub struct Chat {
     pub model_picker: ModelPicker,
     pub files: Vec<Attachment>,
     pub prepend_buf: String,
+    #[serde(skip)]
+    pub edit_files: Vec<Attachment>,
 
     #[serde(default = "generate_id")]
     pub id: u64,
@@ -491,6 +1191,25 @@ pub struct Chat {
     pub retry_message_idx: Option<usize>,
     #[serde(skip)]
     pub chatbox_highlighter: MemoizedEasymarkHighlighter,
+    /// When the pending request was queued, if it waits for a free generation slot.
+    #[serde(skip)]
+    pub queued_at: Option<Instant>,
+    /// A response finished while another chat was selected.
+    pub unread: bool,
+    /// Side-by-side comparison of several models, if compare mode is on.
+    #[serde(skip)]
+    pub compare: Option<Comparison>,
+    /// Context cache holding the system prompt and pinned attachments, if caching is on.
+    pub context_cache: Option<CachedPrefix>,
+    /// Prompt template whose variables are being filled in.
+    #[serde(skip)]
+    pub template_form: Option<TemplateForm>,
+    /// Name of the API key profile the chat uses, the default key if `None`.
+    #[serde(alias = "api_key")]
+    pub key_profile: Option<String>,
+    /// Index of the response a continuation request is extending.
+    #[serde(skip)]
+    continued: Option<usize>,
 }
 
 impl Default for Chat {
@@ -508,195 +1227,540 @@ impl Default for Chat {
             model_picker: ModelPicker::default(),
             files: Vec::new(),
             prepend_buf: String::new(),
+            edit_files: Vec::new(),
             token_count: None,
             last_content_hash: 0,
             last_token_check: None,
+            queued_at: None,
+            unread: false,
+            compare: None,
+            context_cache: None,
+            template_form: None,
+            key_profile: None,
+            continued: None,
         }
     }
 }
 
-async fn request_completion(
-    gemini: Gemini,
-    messages: Vec<Message>,
-    handle: &CompletionFlowerHandle,
-    stop_generating: Arc<AtomicBool>,
-    index: usize,
+type ResponseStream =
+    Pin<Box<dyn Stream<Item = Result<GenerationResponse, CompletionError>> + Send>>;
+
+/// Prompt used to fold the older messages into a summary.
+const SUMMARY_PROMPT: &str = "Summarize the conversation so far. Keep every fact, decision, \
+name, number and piece of code that may matter later, and leave out small talk. \
+Answer with the summary only.";
+
+/// Upper bound on model <-> tool round trips for a single response.
+const MAX_TOOL_ROUNDS: usize = 8;
+
+/// The backend a completion is requested from.
+enum CompletionClient {
+    Gemini(Gemini),
+    CodeAssist(gemini_code_assist_adapter::CodeAssistClient),
+}
+
+/// Request parameters shared by both backends.
+struct CompletionOptions {
     use_streaming: bool,
     public_file_upload: bool,
     generation_config: GenerationConfig,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
-    log::info!(
-        "Requesting completion... (history length: {})",
-        messages.len()
-    );
-
-    let history = crate::chat_completion::build_history(
-        &gemini,
-        &messages,
-        None,
-        public_file_upload,
-        Some((index, handle)),
-    )
-    .await?;
-
-    // 2. Prepare the request builder
-    let mut content_builder = gemini.generate_content();
+    system_prompt: Option<String>,
+    tools: Vec<gemini_rust::Tool>,
+    safety_settings: Vec<SafetySetting>,
+    retry: RetryPolicy,
+    /// Lifetime of the context cache, `None` if caching is disabled.
+    cache_ttl: Option<Duration>,
+    /// Context cache created by a previous request of the chat.
+    cache: Option<CachedPrefix>,
+    /// Name of the cache the request refers to. The system prompt and tools are part of it.
+    cached_content: Option<String>,
+    /// Keys to move to when the quota runs out, `None` if rotation is off.
+    key_rotation: Option<KeyRotation>,
+    /// Directories the local tools may access.
+    tool_scope: ToolScope,
+}
 
-    // Inject constructed history
-    content_builder.contents.extend(history);
+/// The API keys a request can move to, in the order they are tried.
+struct KeyRotation {
+    model_picker: ModelPicker,
+    next: VecDeque<ApiKeyProfile>,
+}
 
-    // Apply configuration
-    let content_builder_final = content_builder
-        .with_safety_settings(SAFETY_SETTINGS.to_vec())
-        .with_generation_config(generation_config);
+impl KeyRotation {
+    /// Rotation for a request using `current`, `None` if rotation is off or the chat
+    /// doesn't use an API key. Code Assist requests stay on the signed-in account.
+    fn for_request(
+        settings: &Settings,
+        current: Option<&ApiKeyProfile>,
+        model_picker: &ModelPicker,
+    ) -> Option<Self> {
+        if !settings.rotate_keys || settings.auth_method != widgets::AuthMethod::ApiKey {
+            return None;
+        }
+        current.map(|profile| Self::new(settings, &profile.name, model_picker.clone()))
+    }
 
-    let mut response_text = String::new();
-    let mut final_usage = None;
+    /// Keys following `current` in the settings, wrapping around.
+    fn new(settings: &Settings, current: &str, model_picker: ModelPicker) -> Self {
+        let profiles = settings.key_profiles();
+        let start = profiles
+            .iter()
+            .position(|p| p.name == current)
+            .map_or(0, |i| i + 1);
+        let mut next: VecDeque<_> = profiles.into_iter().collect();
+        next.rotate_left(start);
+        next.retain(|p| p.name != current);
+        Self { model_picker, next }
+    }
 
-    // Helper closure for cancellation polling
-    let check_cancellation = || async {
-        loop {
-            if stop_generating.load(Ordering::SeqCst) {
-                stop_generating.store(false, Ordering::SeqCst);
-                log::warn!("Request cancelled");
-                return;
+    /// Client for the next key, with the key name.
+    fn next_client(&mut self) -> Option<(String, Gemini)> {
+        while let Some(profile) = self.next.pop_front() {
+            match self.model_picker.create_client(&profile) {
+                Ok(gemini) => return Some((profile.name, gemini)),
+                Err(e) => log::error!(
+                    "failed to create client for the `{}` key: {e}",
+                    profile.name
+                ),
             }
-            tokio::time::sleep(Duration::from_millis(100)).await;
         }
-    };
+        None
+    }
+}
 
-    // 3. Execute Request (Streaming or Blocking)
-    if use_streaming {
-        // Start the stream, respecting cancellation immediately
-        let stream_result = tokio::select! {
-            _ = check_cancellation() => None,
-            res = content_builder_final.execute_stream() => Some(res),
+impl CompletionClient {
+    async fn build_history(
+        &self,
+        messages: &[Message],
+        public_file_upload: bool,
+        status_channel: Option<(usize, &CompletionFlowerHandle)>,
+    ) -> Result<Vec<Content>, CompletionError> {
+        let history = match self {
+            Self::Gemini(gemini) => {
+                crate::chat_completion::build_history(
+                    gemini,
+                    messages,
+                    None,
+                    public_file_upload,
+                    status_channel,
+                )
+                .await?
+            }
+            Self::CodeAssist(_) => {
+                // Code Assist has no File API, every attachment is sent inline
+                let dummy_client = Gemini::new("").map_err(CompletionError::from_error)?;
+                crate::chat_completion::build_history(
+                    &dummy_client,
+                    messages,
+                    None,
+                    false,
+                    status_channel,
+                )
+                .await?
+            }
         };
+        Ok(history)
+    }
 
-        let mut stream = match stream_result {
-            Some(Ok(s)) => s.into_stream(),
-            Some(Err(e)) => return Err(e.into()),
-            None => {
-                handle.success((index, response_text, final_usage));
-                return Ok(());
+    /// Asks the model to summarize `messages`, so they can be replaced by the
+    /// summary in later requests.
+    async fn summarize(
+        &self,
+        messages: &[Message],
+        options: &CompletionOptions,
+    ) -> Result<String, CompletionError> {
+        // Everything after the previous summary is summarized again, together with it
+        let mut messages = messages.to_vec();
+        let previous = messages[..messages.len() - 1]
+            .iter()
+            .rposition(|m| m.summary.is_some());
+        for (i, message) in messages.iter_mut().enumerate() {
+            if previous.is_none_or(|p| i > p) {
+                message.context = ContextMark::Included;
             }
+        }
+        let model = messages.last().map(|m| m.model).unwrap_or_default();
+        messages.push(Message::user(SUMMARY_PROMPT.to_string(), model, Vec::new()));
+
+        let history = self
+            .build_history(&messages, options.public_file_upload, None)
+            .await?;
+        let options = CompletionOptions {
+            use_streaming: false,
+            public_file_upload: options.public_file_upload,
+            generation_config: GenerationConfig::default(),
+            system_prompt: None,
+            tools: Vec::new(),
+            safety_settings: options.safety_settings.clone(),
+            retry: options.retry,
+            cache_ttl: None,
+            cache: None,
+            cached_content: None,
+            key_rotation: None,
+            tool_scope: ToolScope::default(),
         };
 
-        log::info!("Reading stream response...");
-
-        // Consume the stream
-        loop {
-            tokio::select! {
-                _ = check_cancellation() => {
-                    log::info!("Streaming generation cancelled by user.");
-                    break;
+        let mut summary = String::new();
+        let mut stream = self.generate(history, &options).await?;
+        while let Some(response) = stream.next().await {
+            let response = response?;
+            let parts = response
+                .candidates
+                .first()
+                .and_then(|c| c.content.parts.as_ref());
+            for part in parts.into_iter().flatten() {
+                if let Part::Text { text, thought, .. } = part {
+                    if !thought.unwrap_or(false) {
+                        summary.push_str(text);
+                    }
                 }
-                next_item = stream.next() => {
-                    match next_item {
-                        Some(Ok(res)) => {
-                            // Capture usage metadata if available
-                            if let Some(usage) = res.usage_metadata {
-                                final_usage = Some(usage);
-                            }
-
-                            // Process candidates
-                            if let Some(candidate) = res.candidates.first() {
-                                if let Some(parts) = &candidate.content.parts {
-                                    for part in parts {
-                                        // Send intermediate part to UI
-                                        handle.send((index, ChatProgress::Part(part.clone())));
+            }
+        }
+        if summary.trim().is_empty() {
+            return Err(CompletionError::Other(
+                "The model returned an empty summary".to_string(),
+            ));
+        }
+        Ok(summary)
+    }
 
-                                        // Accumulate full text for final state
-                                        if let Part::Text { text, .. } = part {
-                                            response_text += &text;
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                        Some(Err(e)) => return Err(e.into()),
-                        None => break, // Stream exhausted
+    /// Sends a single request. Non-streaming responses are returned as a one-item stream.
+    async fn generate(
+        &self,
+        contents: Vec<Content>,
+        options: &CompletionOptions,
+    ) -> Result<ResponseStream, CompletionError> {
+        match self {
+            Self::Gemini(gemini) => {
+                let mut builder = gemini.generate_content();
+                builder.contents = contents;
+                if let Some(name) = &options.cached_content {
+                    builder = builder.with_cached_content(&gemini.get_cached_content(name));
+                } else {
+                    if let Some(prompt) = &options.system_prompt {
+                        builder = builder.with_system_prompt(prompt.clone());
+                    }
+                    for tool in &options.tools {
+                        builder = builder.with_tool(tool.clone());
                     }
                 }
+                let builder = builder
+                    .with_safety_settings(options.safety_settings.clone())
+                    .with_generation_config(options.generation_config.clone());
+
+                if options.use_streaming {
+                    let stream = builder
+                        .execute_stream()
+                        .await
+                        .map_err(CompletionError::from_error)?
+                        .into_stream();
+                    Ok(Box::pin(stream.map_err(CompletionError::from_error)))
+                } else {
+                    let response = builder
+                        .execute()
+                        .await
+                        .map_err(CompletionError::from_error)?;
+                    Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+                }
+            }
+            Self::CodeAssist(client) => {
+                let cached = options.cached_content.is_some();
+                let request = gemini_rust::GenerateContentRequest {
+                    contents,
+                    generation_config: Some(options.generation_config.clone()),
+                    safety_settings: Some(options.safety_settings.clone()),
+                    tools: (!cached && !options.tools.is_empty()).then(|| options.tools.clone()),
+                    tool_config: None,
+                    system_instruction: options
+                        .system_prompt
+                        .clone()
+                        .filter(|_| !cached)
+                        .map(Content::text),
+                    cached_content: options.cached_content.clone(),
+                };
+
+                if options.use_streaming {
+                    let stream = client.generate_content_stream(&request).await?;
+                    Ok(Box::pin(stream.map_err(CompletionError::from)))
+                } else {
+                    let response = client.generate_content(&request).await?;
+                    Ok(Box::pin(futures::stream::once(async move { Ok(response) })))
+                }
             }
         }
-    } else {
-        log::info!("Sending non-streaming request...");
+    }
+}
+
+/// Delay before the next attempt, or `None` if the request should fail right away.
+/// The delay requested by the server takes precedence over the backoff.
+fn retry_delay(policy: &RetryPolicy, error: &CompletionError, attempt: u32) -> Option<Duration> {
+    if !policy.enabled || attempt >= policy.max_attempts || !error.is_transient() {
+        return None;
+    }
+    let server_delay = error.api().and_then(|api| api.retry_delay);
+    Some(server_delay.unwrap_or_else(|| policy.backoff(attempt)))
+}
 
+/// Asks the user to allow a tool call and waits for the answer.
+/// Returns `None` if the request was cancelled meanwhile.
+async fn confirm_tool(
+    handle: &CompletionFlowerHandle,
+    index: usize,
+    name: &str,
+    stop_generating: &AtomicBool,
+) -> Option<bool> {
+    let (approval, mut answer) = ToolApproval::new();
+    handle.send((
+        index,
+        ChatProgress::Status {
+            message: format!("Waiting for your approval to run `{name}`..."),
+        },
+    ));
+    handle.send((
+        index,
+        ChatProgress::ToolApproval {
+            name: name.to_string(),
+            approval,
+        },
+    ));
+    loop {
         tokio::select! {
-            _ = check_cancellation() => {}
-            result = content_builder_final.execute() => {
-                match result {
-                    Ok(response) => {
-                        log::info!("Non-streaming response received.");
-                        final_usage = response.usage_metadata;
-
-                        if let Some(candidate) = response.candidates.first() {
-                            if let Some(parts) = &candidate.content.parts {
-                                for part in parts {
-                                    handle.send((index, ChatProgress::Part(part.clone())));
-                                    if let Part::Text { text, .. } = part {
-                                        response_text += text;
-                                    }
-                                }
-                            }
-                        }
-                    }
-                    Err(err) => return Err(Box::new(err)),
+            // Dropped with the chat, which is as good as a denial
+            allowed = &mut answer => return Some(allowed.unwrap_or(false)),
+            _ = tokio::time::sleep(Duration::from_millis(100)) => {
+                if stop_generating.load(Ordering::SeqCst) {
+                    stop_generating.store(false, Ordering::SeqCst);
+                    log::warn!("Request cancelled");
+                    return None;
                 }
             }
         }
     }
+}
 
-    log::info!(
-        "Completion request finished. Total response length: {}",
-        response_text.len()
-    );
+/// Waits before a retry while showing a countdown. Returns `false` if cancelled.
+async fn wait_for_retry(
+    handle: &CompletionFlowerHandle,
+    index: usize,
+    stop_generating: &AtomicBool,
+    delay: Duration,
+    attempt: u32,
+    max_attempts: u32,
+) -> bool {
+    let deadline = Instant::now() + delay;
+    let mut shown_secs = None;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return true;
+        }
+        if stop_generating.load(Ordering::SeqCst) {
+            stop_generating.store(false, Ordering::SeqCst);
+            log::warn!("Request cancelled");
+            return false;
+        }
 
-    // Notify UI of success
-    handle.success((index, response_text, final_usage));
+        let secs = remaining.as_secs_f32().ceil() as u64;
+        if shown_secs != Some(secs) {
+            shown_secs = Some(secs);
+            handle.send((
+                index,
+                ChatProgress::Status {
+                    message: format!(
+                        "Retrying in {secs}s (attempt {}/{max_attempts})...",
+                        attempt + 1
+                    ),
+                },
+            ));
+        }
+        tokio::time::sleep(remaining.min(Duration::from_millis(100))).await;
+    }
+}
 
-    Ok(())
+/// Adds up the token counts of a response and its continuation, both calls are billed.
+fn merge_usage(mut first: UsageMetadata, second: UsageMetadata) -> UsageMetadata {
+    fn sum<T: std::ops::Add<Output = T>>(a: Option<T>, b: Option<T>) -> Option<T> {
+        match (a, b) {
+            (Some(a), Some(b)) => Some(a + b),
+            (a, b) => a.or(b),
+        }
+    }
+    first.prompt_token_count = sum(first.prompt_token_count, second.prompt_token_count);
+    first.candidates_token_count = sum(first.candidates_token_count, second.candidates_token_count);
+    first.total_token_count = sum(first.total_token_count, second.total_token_count);
+    first
 }
 
-async fn request_completion_code_assist(
-    client: gemini_code_assist_adapter::CodeAssistClient,
-    messages: Vec<Message>,
+/// Appends a response part, merging streamed text chunks into a single part.
+fn push_response_part(parts: &mut Vec<Part>, part: Part) {
+    if let (
+        Some(Part::Text {
+            text,
+            thought,
+            thought_signature,
+        }),
+        Part::Text {
+            text: chunk,
+            thought: chunk_thought,
+            thought_signature: chunk_signature,
+        },
+    ) = (parts.last_mut(), &part)
+    {
+        if thought.unwrap_or(false) == chunk_thought.unwrap_or(false) {
+            text.push_str(chunk);
+            if chunk_signature.is_some() {
+                *thought_signature = chunk_signature.clone();
+            }
+            return;
+        }
+    }
+    parts.push(part);
+}
+
+/// Moves the request to the next API key if `error` means the quota of the current one
+/// ran out. Uploaded files and caches belong to the old key's project, so they are dropped.
+fn switch_key(
+    error: &CompletionError,
+    client: &mut CompletionClient,
+    messages: &mut [Message],
+    options: &mut CompletionOptions,
+    handle: &CompletionFlowerHandle,
+    index: usize,
+) -> bool {
+    // Only API key clients can move to another key
+    if !error.is_quota_exhausted() || matches!(client, CompletionClient::CodeAssist(_)) {
+        return false;
+    }
+    let Some((name, gemini)) = options
+        .key_rotation
+        .as_mut()
+        .and_then(KeyRotation::next_client)
+    else {
+        return false;
+    };
+
+    log::warn!("Quota exhausted, switching to the `{name}` API key");
+    handle.send((index, ChatProgress::ApiKey { name }));
+    *client = CompletionClient::Gemini(gemini);
+    options.cache = None;
+    options.cached_content = None;
+    for attachment in messages.iter_mut().flat_map(|m| m.files.iter_mut()) {
+        attachment.state = AttachmentState::Local;
+    }
+    true
+}
+
+/// Builds the request contents, moving to the next API key while the quota of the
+/// current one is exhausted.
+async fn prepare_history(
+    client: &mut CompletionClient,
+    messages: &mut [Message],
+    handle: &CompletionFlowerHandle,
+    index: usize,
+    options: &mut CompletionOptions,
+) -> Result<Vec<Content>, CompletionError> {
+    loop {
+        match build_request_history(client, messages, handle, index, options).await {
+            Err(e) if switch_key(&e, client, messages, options, handle, index) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Summarizes the older messages if needed, sets up the context cache and builds the
+/// request contents.
+async fn build_request_history(
+    client: &CompletionClient,
+    messages: &mut [Message],
+    handle: &CompletionFlowerHandle,
+    index: usize,
+    options: &mut CompletionOptions,
+) -> Result<Vec<Content>, CompletionError> {
+    if let Some(end) = crate::context::pending_summary(messages) {
+        handle.send((
+            index,
+            ChatProgress::Status {
+                message: "Summarizing older messages...".to_string(),
+            },
+        ));
+        let summary = client.summarize(&messages[..=end], options).await?;
+        handle.send((
+            index,
+            ChatProgress::ContextSummary {
+                idx: end,
+                summary: summary.clone(),
+            },
+        ));
+        messages[end].summary = Some(summary);
+    }
+
+    // Pinned files are dropped from the copy only, a later key may need to upload them
+    let mut messages = messages.to_vec();
+
+    // Send the stable prefix through the context cache
+    if let (CompletionClient::Gemini(gemini), Some(ttl)) = (client, options.cache_ttl) {
+        let model = messages.last().map(|m| m.model).unwrap_or_default();
+        let prefix = crate::cache::Prefix::new(
+            model,
+            options.system_prompt.as_deref(),
+            &options.tools,
+            &messages,
+        );
+        let cache = prefix
+            .ensure_cache(
+                gemini,
+                ttl,
+                options.cache.as_ref(),
+                options.public_file_upload,
+                Some((index, handle)),
+            )
+            .await;
+        match cache {
+            Ok(Some(cache)) => {
+                if options.cache.as_ref() != Some(&cache) {
+                    handle.send((
+                        index,
+                        ChatProgress::ContextCache {
+                            cache: cache.clone(),
+                        },
+                    ));
+                }
+                for message in &mut messages {
+                    message.files.retain(|f| !f.pinned);
+                }
+                options.cached_content = Some(cache.name);
+            }
+            Ok(None) => {}
+            // Only reported once, the prefix isn't tried again
+            Err(e) => log::warn!("context cache unavailable, sending the full request: {e}"),
+        }
+    }
+
+    client
+        .build_history(&messages, options.public_file_upload, Some((index, handle)))
+        .await
+}
+
+async fn request_completion(
+    mut client: CompletionClient,
+    mut messages: Vec<Message>,
     handle: &CompletionFlowerHandle,
     stop_generating: Arc<AtomicBool>,
     index: usize,
-    use_streaming: bool,
-    generation_config: GenerationConfig,
-) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    mut options: CompletionOptions,
+) -> Result<(), CompletionError> {
     log::info!(
-        "Requesting completion via Code Assist... (history length: {})",
+        "Requesting completion... (history length: {})",
         messages.len()
     );
 
-    let dummy_client = Gemini::new("")?;
-
-    let history = crate::chat_completion::build_history(
-        &dummy_client,
-        &messages,
-        None,
-        false,
-        Some((index, handle)),
-    )
-    .await?;
-
-    let gemini_request = gemini_rust::GenerateContentRequest {
-        contents: history,
-        generation_config: Some(generation_config),
-        safety_settings: Some(SAFETY_SETTINGS.to_vec()),
-        tools: None,
-        tool_config: None,
-        system_instruction: None,
-        cached_content: None,
-    };
+    let mut history =
+        prepare_history(&mut client, &mut messages, handle, index, &mut options).await?;
 
     let mut response_text = String::new();
     let mut final_usage = None;
 
+    // Helper closure for cancellation polling
     let check_cancellation = || async {
         loop {
             if stop_generating.load(Ordering::SeqCst) {
@@ -708,67 +1772,277 @@ async fn request_completion_code_assist(
         }
     };
 
-    if use_streaming {
-        let mut stream = client.generate_content_stream(&gemini_request).await?;
+    'rounds: for round in 0..MAX_TOOL_ROUNDS {
+        let mut attempt = 1;
+        let mut candidate_texts: Vec<String> = Vec::new();
+        let (model_parts, cancelled) = 'attempt: loop {
+            // Start the request, respecting cancellation immediately
+            let stream_result = tokio::select! {
+                _ = check_cancellation() => None,
+                res = client.generate(history.clone(), &options) => Some(res),
+            };
+
+            let mut stream = match stream_result {
+                Some(Ok(s)) => s,
+                Some(Err(e)) => {
+                    // Only the first request can move to another key, tool calls were
+                    // made with the uploads of the current one
+                    if round == 0
+                        && switch_key(&e, &mut client, &mut messages, &mut options, handle, index)
+                    {
+                        history = prepare_history(
+                            &mut client,
+                            &mut messages,
+                            handle,
+                            index,
+                            &mut options,
+                        )
+                        .await?;
+                        continue;
+                    }
+                    let Some(delay) = retry_delay(&options.retry, &e, attempt) else {
+                        return Err(e);
+                    };
+                    log::info!("request failed, retrying in {delay:?}: {e}");
+                    if !wait_for_retry(
+                        handle,
+                        index,
+                        &stop_generating,
+                        delay,
+                        attempt,
+                        options.retry.max_attempts,
+                    )
+                    .await
+                    {
+                        break (Vec::new(), true);
+                    }
+                    attempt += 1;
+                    continue;
+                }
+                None => break (Vec::new(), true),
+            };
+
+            log::info!("Reading response...");
+
+            let mut model_parts = Vec::new();
+            let mut cancelled = false;
+            candidate_texts.clear();
+            loop {
+                tokio::select! {
+                    _ = check_cancellation() => {
+                        log::info!("Generation cancelled by user.");
+                        cancelled = true;
+                        break;
+                    }
+                    next_item = stream.next() => {
+                        match next_item {
+                            Some(Ok(res)) => {
+                                if let Some((finish_reason, safety_ratings)) =
+                                    crate::safety::feedback(&res)
+                                {
+                                    handle.send((
+                                        index,
+                                        ChatProgress::Finish {
+                                            finish_reason,
+                                            safety_ratings,
+                                        },
+                                    ));
+                                }
+
+                                // Capture usage metadata if available
+                                if let Some(usage) = res.usage_metadata {
+                                    final_usage = Some(usage);
+                                }
+
+                                // Process candidates
+                                for (position, candidate) in res.candidates.iter().enumerate() {
+                                    let candidate_index =
+                                        candidate.index.map_or(position, |i| i as usize);
+                                    if candidate_index > 0 {
+                                        // Other candidates only keep their answer, shown as tabs
+                                        if candidate_texts.len() < candidate_index {
+                                            candidate_texts.resize(candidate_index, String::new());
+                                        }
+                                        let text = &mut candidate_texts[candidate_index - 1];
+                                        for part in candidate.content.parts.iter().flatten() {
+                                            if let Part::Text { text: chunk, thought, .. } = part {
+                                                if !thought.unwrap_or(false) {
+                                                    text.push_str(chunk);
+                                                }
+                                            }
+                                        }
+                                        continue;
+                                    }
 
-        log::info!("Reading Code Assist stream response...");
+                                    let sources = Source::from_candidate(candidate);
+                                    if !sources.is_empty() {
+                                        handle.send((index, ChatProgress::Sources { sources }));
+                                    }
+                                    if let Some(parts) = &candidate.content.parts {
+                                        for part in parts {
+                                            // Send intermediate part to UI
+                                            if let Part::InlineData { inline_data, .. } = part {
+                                                match save_inline_data(inline_data).await {
+                                                    Ok(attachment) => handle.send((
+                                                        index,
+                                                        ChatProgress::GeneratedFile { attachment },
+                                                    )),
+                                                    Err(e) => {
+                                                        log::error!("failed to save generated file: {e}")
+                                                    }
+                                                }
+                                            } else {
+                                                handle.send((index, ChatProgress::Part(part.clone())));
+                                            }
 
-        loop {
-            tokio::select! {
-                _ = check_cancellation() => {
-                    log::info!("Code Assist generation cancelled.");
-                    break;
-                }
-                next_item = futures::StreamExt::next(&mut stream) => {
-                    match next_item {
-                        Some(Ok(res)) => {
-                            if let Some(usage) = res.usage_metadata {
-                                final_usage = Some(usage);
-                            }
-                            if let Some(candidate) = res.candidates.first() {
-                                if let Some(parts) = &candidate.content.parts {
-                                    for part in parts {
-                                        handle.send((index, ChatProgress::Part(part.clone())));
-                                        if let Part::Text { text, .. } = part {
-                                            response_text += &text;
+                                            // Accumulate full text for final state
+                                            if let Part::Text { text, .. } = part {
+                                                response_text += text;
+                                            }
+                                            push_response_part(&mut model_parts, part.clone());
                                         }
                                     }
                                 }
                             }
-                        }
-                        Some(Err(e)) => return Err(e.into()),
-                        None => break,
-                    }
-                }
-            }
-        }
-    } else {
-        tokio::select! {
-            _ = check_cancellation() => {
-                log::info!("Code Assist generation cancelled.");
-            }
-            result = client.generate_content(&gemini_request) => {
-                match result {
-                    Ok(response) => {
-                        final_usage = response.usage_metadata;
-                        if let Some(candidate) = response.candidates.first() {
-                            if let Some(parts) = &candidate.content.parts {
-                                for part in parts {
-                                    handle.send((index, ChatProgress::Part(part.clone())));
-                                    if let Part::Text { text, .. } = part {
-                                        response_text += text;
-                                    }
+                            Some(Err(e)) => {
+                                if round == 0
+                                    && model_parts.is_empty()
+                                    && switch_key(
+                                        &e,
+                                        &mut client,
+                                        &mut messages,
+                                        &mut options,
+                                        handle,
+                                        index,
+                                    )
+                                {
+                                    history = prepare_history(
+                                        &mut client,
+                                        &mut messages,
+                                        handle,
+                                        index,
+                                        &mut options,
+                                    )
+                                    .await?;
+                                    continue 'attempt;
                                 }
+                                // Nothing was shown yet, so the request can be safely repeated
+                                let delay = model_parts
+                                    .is_empty()
+                                    .then(|| retry_delay(&options.retry, &e, attempt))
+                                    .flatten();
+                                let Some(delay) = delay else {
+                                    return Err(e);
+                                };
+                                log::info!("stream failed, retrying in {delay:?}: {e}");
+                                if !wait_for_retry(
+                                    handle,
+                                    index,
+                                    &stop_generating,
+                                    delay,
+                                    attempt,
+                                    options.retry.max_attempts,
+                                )
+                                .await
+                                {
+                                    break 'attempt (Vec::new(), true);
+                                }
+                                attempt += 1;
+                                continue 'attempt;
                             }
+                            None => break, // Stream exhausted
                         }
                     }
-                    Err(e) => return Err(e.into()),
                 }
             }
+            break (model_parts, cancelled);
+        };
+
+        if !candidate_texts.is_empty() {
+            handle.send((
+                index,
+                ChatProgress::Candidates {
+                    texts: candidate_texts,
+                },
+            ));
+        }
+
+        let calls: Vec<_> = model_parts
+            .iter()
+            .filter_map(|part| match part {
+                Part::FunctionCall { function_call, .. } => Some(function_call.clone()),
+                _ => None,
+            })
+            .collect();
+        if cancelled || calls.is_empty() {
+            break;
+        }
+
+        // Run the requested tools locally and feed their results back to the model
+        let mut responses = Vec::with_capacity(calls.len());
+        for call in calls {
+            let allowed = if crate::tools::REGISTRY.needs_confirmation(&call.name) {
+                confirm_tool(handle, index, &call.name, &stop_generating).await
+            } else {
+                Some(true)
+            };
+            let response = match allowed {
+                Some(true) => {
+                    handle.send((
+                        index,
+                        ChatProgress::Status {
+                            message: format!("Running tool `{}`...", call.name),
+                        },
+                    ));
+                    crate::tools::REGISTRY
+                        .execute(&call.name, call.args.clone(), &options.tool_scope)
+                        .await
+                }
+                Some(false) => {
+                    log::info!("the user denied the call of tool `{}`", call.name);
+                    serde_json::json!({ "error": "The user denied this call" })
+                }
+                None => {
+                    handle.send((
+                        index,
+                        ChatProgress::ToolResult {
+                            name: call.name,
+                            response: serde_json::json!({ "error": "Cancelled" }),
+                        },
+                    ));
+                    break 'rounds;
+                }
+            };
+            handle.send((
+                index,
+                ChatProgress::ToolResult {
+                    name: call.name.clone(),
+                    response: response.clone(),
+                },
+            ));
+            responses.push(Part::FunctionResponse {
+                function_response: FunctionResponse::new(call.name, response),
+            });
         }
+
+        history.push(Content {
+            parts: Some(model_parts),
+            role: Some(Role::Model),
+        });
+        history.push(Content {
+            parts: Some(responses),
+            role: Some(Role::User),
+        });
     }
 
+    log::info!(
+        "Completion request finished. Total response length: {}",
+        response_text.len()
+    );
+
+    // Notify UI of success
     handle.success((index, response_text, final_usage));
+
     Ok(())
 }
 
@@ -893,8 +2167,9 @@ impl Chat {
             return;
         }
 
-        // remove old error messages
-        self.messages.retain(|m| !m.is_error);
+        // remove old error messages, unless they hold other versions of the conversation
+        self.messages
+            .retain(|m| !m.is_error || !m.alternatives.is_empty());
 
         let prompt = self.chatbox.trim_end().to_string();
         let model = self.model_picker.selected;
@@ -910,67 +2185,160 @@ impl Chat {
 
         self.messages.push(Message::assistant(String::new(), model));
 
-        self.spawn_completion(settings, None);
+        self.spawn_completion(settings);
     }
 
-    fn spawn_completion(&self, settings: &Settings, target_index: Option<usize>) {
+    /// Sends the chatbox contents to every model selected in compare mode.
+    fn send_comparison(&mut self, settings: &Settings) {
+        if self.chatbox.is_empty() && self.files.is_empty() {
+            return;
+        }
+        let Some(mut compare) = self.compare.take() else {
+            return;
+        };
+
+        if compare.contenders.is_empty() {
+            log::warn!("Select at least one model to compare");
+        } else {
+            let prompt = self.chatbox.trim_end().to_string();
+            if self.summary.is_empty() {
+                self.summary = make_summary(&prompt);
+            }
+            compare.start(self, prompt, std::mem::take(&mut self.files), settings);
+            self.chatbox.clear();
+        }
+        self.compare = Some(compare);
+    }
+
+    /// Polls the compared models. Returns whether any of them is still generating.
+    pub fn poll_comparison(&mut self, modal: &mut Modal) -> bool {
+        let Some(compare) = &mut self.compare else {
+            return false;
+        };
+        compare.poll(modal);
+        compare.is_active()
+    }
+
+    /// Requests a response for the last message, which must be an assistant placeholder.
+    /// With a concurrency limit, the request waits in the queue until `Sessions` starts it.
+    pub fn spawn_completion(&mut self, settings: &Settings) {
+        let policy: ContextPolicy = *self.model_picker.context_policy();
+        let input_limit = self.model_picker.capabilities().input_token_limit;
+        let token_count = crate::context::estimate_tokens(&self.messages);
+        policy.apply(&mut self.messages, input_limit, token_count);
+
+        if settings.max_concurrent_generations.is_some() {
+            self.queued_at = Some(Instant::now());
+            if let Some(placeholder) = self.messages.last_mut() {
+                placeholder.status_message = Some("Waiting in queue...".to_string());
+            }
+        } else {
+            self.start_completion(settings);
+        }
+    }
+
+    /// Starts the queued request.
+    pub fn start_queued(&mut self, settings: &Settings) {
+        self.queued_at = None;
+        if let Some(placeholder) = self.messages.last_mut() {
+            placeholder.status_message = None;
+            placeholder.requested_at = Instant::now();
+        }
+        self.start_completion(settings);
+    }
+
+    /// Drops the queued request, as if it was cancelled right after starting.
+    pub fn cancel_queued(&mut self) {
+        self.queued_at = None;
+        if let Some(placeholder) = self.messages.last_mut() {
+            placeholder.finish_generating();
+            placeholder.status_message = None;
+        }
+    }
+
+    /// Switches the chat to another API key. Files uploaded with the old key and the
+    /// context cache belong to its project, so they are dropped.
+    pub fn set_key_profile(&mut self, name: Option<String>) {
+        if self.key_profile == name {
+            return;
+        }
+        self.key_profile = name;
+        self.context_cache = None;
+        for attachment in self.messages.iter_mut().flat_map(|m| m.files.iter_mut()) {
+            attachment.state = AttachmentState::Local;
+        }
+    }
+
+    pub fn start_completion(&self, settings: &Settings) {
         let handle = self.flower.handle();
         let stop_generation = self.stop_generating.clone();
         let mut messages = self.messages.clone();
-        let index = target_index.unwrap_or(self.messages.len() - 1);
-
-        if settings.include_thoughts_in_history {
-            for msg in &mut messages {
-                if msg.is_thought {
-                    msg.is_thought = false;
-                    msg.content.insert_str(0, "MY INNER REFLECTIONS: ");
-                    msg.content
-                        .push_str(r"--- end of inner reflections ---\r\n")
-                }
+        let index = self.messages.len() - 1;
+
+        // Thoughts are sent back as thought parts, and always keep their signatures
+        for msg in messages.iter_mut().filter(|m| m.is_thought) {
+            if !settings.include_thoughts_in_history {
+                msg.strip_thought_text();
+            } else if msg.parts.is_empty() {
+                // Saved before the parts were stored
+                msg.parts.push(Part::Text {
+                    text: msg.content.clone(),
+                    thought: Some(true),
+                    thought_signature: None,
+                });
             }
         }
 
-        let use_streaming = settings.use_streaming;
-        let public_file_upload = settings.public_file_upload;
-        let generation_config = self.model_picker.get_generation_config();
+        let mut options = CompletionOptions {
+            use_streaming: settings.use_streaming,
+            public_file_upload: settings.public_file_upload,
+            generation_config: self.model_picker.get_generation_config(),
+            system_prompt: self.model_picker.system_instruction(),
+            tools: self.model_picker.tools(),
+            safety_settings: self.model_picker.safety_settings(),
+            retry: settings.retry,
+            cache_ttl: self.model_picker.cache_policy().ttl(),
+            cache: self.context_cache.clone(),
+            cached_content: None,
+            key_rotation: None,
+            tool_scope: self.model_picker.tool_scope(),
+        };
+        if self.continued.is_some() {
+            // The thoughts of the first call are already shown, only the answer is extended
+            if let Some(thinking) = &mut options.generation_config.thinking_config {
+                thinking.include_thoughts = None;
+            }
+        }
         let auth_method = settings.auth_method;
-        let api_key = settings.api_key.clone();
+        let key_profile = settings.key_profile(self.key_profile.as_deref());
         let oauth_token = settings.oauth_token.clone();
         let project_id = settings.project_id.clone();
-        let proxy_path = settings.proxy_path.clone();
         let model_picker = self.model_picker.clone();
+        options.key_rotation =
+            KeyRotation::for_request(settings, key_profile.as_ref(), &model_picker);
 
         tokio::spawn(async move {
             handle.activate();
 
-            match auth_method {
+            let client = match auth_method {
                 crate::widgets::AuthMethod::ApiKey => {
-                    if api_key.is_empty() {
-                        handle.error((index, "API key not set.".to_string()));
+                    let Some(profile) = key_profile else {
+                        handle.error((
+                            index,
+                            CompletionError::Config("API key not set.".to_string()),
+                        ));
                         return;
-                    }
+                    };
 
-                    match model_picker.create_client(&api_key, proxy_path) {
-                        Ok(gemini) => {
-                            let _ = request_completion(
-                                gemini,
-                                messages,
-                                &handle,
-                                stop_generation,
-                                index,
-                                use_streaming,
-                                public_file_upload,
-                                generation_config,
-                            )
-                            .await
-                            .map_err(|e| {
-                                log::error!("failed to request completion: {e}");
-                                handle.error((index, e.to_string()));
-                            });
-                        }
+                    match model_picker.create_client(&profile) {
+                        Ok(gemini) => CompletionClient::Gemini(gemini),
                         Err(e) => {
                             log::error!("failed to create client: {e}");
-                            handle.error((index, format!("Failed to create client: {}", e)));
+                            handle.error((
+                                index,
+                                CompletionError::Config(format!("Failed to create client: {e}")),
+                            ));
+                            return;
                         }
                     }
                 }
@@ -978,8 +2346,10 @@ impl Chat {
                     if oauth_token.is_empty() || project_id.is_empty() {
                         handle.error((
                             index,
-                            "OAuth token or Project ID not set. Please login in settings."
-                                .to_string(),
+                            CompletionError::Config(
+                                "OAuth token or Project ID not set. Please login in settings."
+                                    .to_string(),
+                            ),
                         ));
                         return;
                     }
@@ -1000,31 +2370,211 @@ impl Chat {
                         log::warn!("Code Assist onboarding warning: {e}");
                     }
 
-                    let _ = request_completion_code_assist(
-                        client,
-                        messages,
-                        &handle,
-                        stop_generation,
-                        index,
-                        use_streaming,
-                        generation_config,
-                    )
-                    .await
-                    .map_err(|e| {
-                        log::error!("failed to request completion via Code Assist: {e}");
-                        handle.error((index, e.to_string()));
-                    });
+                    CompletionClient::CodeAssist(client)
                 }
-            }
+            };
+
+            let _ = request_completion(client, messages, &handle, stop_generation, index, options)
+                .await
+                .map_err(|e| {
+                    log::error!("failed to request completion: {e}");
+                    handle.error((index, e));
+                });
         });
     }
 
+    /// Returns the index of the answer of the response started at `idx`, so that
+    /// response metadata is attached to it rather than to thoughts or tool calls.
+    fn answer_idx(&self, idx: usize) -> usize {
+        self.messages
+            .iter()
+            .enumerate()
+            .skip(idx)
+            .rev()
+            .find(|(_, m)| !m.is_user() && !m.is_block())
+            .map_or(self.messages.len() - 1, |(i, _)| i)
+    }
+
+    /// Returns the index of the first message of the response containing `idx`
+    /// (thoughts and tool calls belong to the same response as the answer).
+    fn turn_start(&self, idx: usize) -> usize {
+        let mut start = idx;
+        while start > 0 && !self.messages[start].is_user() && !self.messages[start - 1].is_user() {
+            start -= 1;
+        }
+        start
+    }
+
+    /// Replaces the conversation from `at` onward with `tail`,
+    /// keeping the old one as an alternative version.
+    fn fork_at(&mut self, at: usize, mut tail: Vec<Message>) {
+        if at >= self.messages.len() || tail.is_empty() {
+            self.messages.extend(tail);
+            return;
+        }
+
+        let mut old_tail = self.messages.split_off(at);
+        let mut alternatives = std::mem::take(&mut old_tail[0].alternatives);
+        let old_index = old_tail[0].branch_index.min(alternatives.len());
+        alternatives.insert(old_index, old_tail);
+
+        tail[0].branch_index = alternatives.len();
+        tail[0].alternatives = alternatives;
+        self.messages.extend(tail);
+    }
+
+    /// Makes another version of the conversation starting at `at` active.
+    fn switch_branch(&mut self, at: usize, branch: usize) {
+        let Some(message) = self.messages.get(at) else {
+            return;
+        };
+        let current = message.branch_index.min(message.alternatives.len());
+        if branch == current || branch > message.alternatives.len() {
+            return;
+        }
+
+        let mut tail = self.messages.split_off(at);
+        let mut versions = std::mem::take(&mut tail[0].alternatives);
+        versions.insert(current, tail);
+
+        let mut new_tail = versions.remove(branch);
+        new_tail[0].alternatives = versions;
+        new_tail[0].branch_index = branch;
+        self.messages.extend(new_tail);
+    }
+
+    fn remove_message(&mut self, idx: usize) {
+        let mut removed = self.messages.remove(idx);
+        if removed.alternatives.is_empty() {
+            return;
+        }
+
+        let mut alternatives = std::mem::take(&mut removed.alternatives);
+        match self.messages.get_mut(idx) {
+            // The next message becomes the start of this version
+            Some(next) if next.alternatives.is_empty() => {
+                next.alternatives = alternatives;
+                next.branch_index = removed.branch_index;
+            }
+            Some(_) => {
+                log::warn!("This message has other versions and can't be removed, switch to another version first");
+                removed.alternatives = alternatives;
+                self.messages.insert(idx, removed);
+            }
+            // Nothing is left of this version, fall back to the closest one
+            None => {
+                let branch = removed.branch_index.min(alternatives.len() - 1);
+                let mut tail = alternatives.remove(branch);
+                tail[0].alternatives = alternatives;
+                tail[0].branch_index = branch;
+                self.messages.extend(tail);
+            }
+        }
+    }
+
+    /// Generates a new version of the response at `idx`, starting after any prepended text.
     fn regenerate_response(&mut self, settings: &Settings, idx: usize) {
-        // todo: regenerate works weird
-        self.messages[idx].content = self.prepend_buf.clone();
-        self.prepend_buf.clear();
+        let start = self.turn_start(idx);
+        let prepended = std::mem::take(&mut self.prepend_buf);
+        self.fork_at(
+            start,
+            vec![Message::assistant(prepended, self.model_picker.selected)],
+        );
+
+        self.spawn_completion(settings);
+    }
+
+    /// Asks the model to go on with the response at `idx`, which was cut off by the output
+    /// token limit. The response is sent back as the model turn and the continuation is
+    /// appended to it. Later messages are kept as another version of the conversation.
+    fn continue_response(&mut self, settings: &Settings, idx: usize) {
+        if idx + 1 < self.messages.len() {
+            let mut message = self.messages[idx].clone();
+            message.alternatives.clear();
+            message.branch_index = 0;
+            self.fork_at(idx, vec![message]);
+        }
+
+        let message = &mut self.messages[idx];
+        message.finish_reason = None;
+        // The other candidates were cut off too, only the selected one is continued
+        message.candidates.clear();
+        message.candidate_index = 0;
+        message.is_generating = true;
+        // Count the time of both calls as the generation time
+        let elapsed = message.generation_time.unwrap_or_default();
+        message.requested_at = Instant::now()
+            .checked_sub(elapsed)
+            .unwrap_or_else(Instant::now);
+
+        self.continued = Some(idx);
+        self.spawn_completion(settings);
+    }
+
+    /// Creates a new version of the conversation with the message at `idx` replaced by the prepend buffer.
+    fn edit_response(&mut self, idx: usize) {
+        let mut tail: Vec<Message> = self.messages[idx..].to_vec();
+        for message in &mut tail {
+            // Older versions stay reachable through the original branch
+            message.alternatives.clear();
+            message.branch_index = 0;
+        }
+        tail[0].content = std::mem::take(&mut self.prepend_buf);
+        if tail[0].schema_errors.is_some() {
+            tail[0].schema_errors = Some(
+                self.model_picker
+                    .structured_output()
+                    .validate(&tail[0].content),
+            );
+            tail[0].parsed_json = None;
+        }
+        self.fork_at(idx, tail);
+    }
+
+    /// Sends the edited user message at `idx` and generates a response for it.
+    /// The following messages are either kept as another version or discarded.
+    fn resend_edited(&mut self, settings: &Settings, idx: usize, truncate: bool) {
+        self.messages[idx].is_editing = false;
+        let model = self.model_picker.selected;
+        let mut message = Message::user(
+            std::mem::take(&mut self.prepend_buf),
+            model,
+            std::mem::take(&mut self.edit_files),
+        );
+        let response = Message::assistant(String::new(), model);
+
+        if truncate {
+            let mut old_tail = self.messages.split_off(idx);
+            message.alternatives = std::mem::take(&mut old_tail[0].alternatives);
+            message.branch_index = old_tail[0].branch_index;
+            self.messages.push(message);
+            self.messages.push(response);
+        } else {
+            self.fork_at(idx, vec![message, response]);
+        }
+
+        self.spawn_completion(settings);
+    }
 
-        self.spawn_completion(settings, Some(idx));
+    /// Adds files to the message being edited, or to the chatbox otherwise.
+    pub fn attach_files(&mut self, files: impl IntoIterator<Item = Attachment>) {
+        if self.messages.iter().any(|m| m.is_editing) {
+            self.edit_files.extend(files);
+        } else {
+            self.files.extend(files);
+        }
+    }
+
+    /// Replaces a failed response with a new attempt, keeping its other versions.
+    fn retry_response(&mut self, settings: &Settings, idx: usize) {
+        let start = self.turn_start(idx);
+        let mut tail = self.messages.split_off(start);
+        let mut message = Message::assistant(String::new(), self.model_picker.selected);
+        message.alternatives = std::mem::take(&mut tail[0].alternatives);
+        message.branch_index = tail[0].branch_index;
+        self.messages.push(message);
+
+        self.spawn_completion(settings);
     }
 
     fn show_chatbox(
@@ -1036,11 +2586,7 @@ impl Chat {
     ) -> ChatAction {
         let mut action = ChatAction::None;
         if let Some(idx) = self.retry_message_idx.take() {
-            self.chatbox = self.messages[idx - 1].content.clone();
-            self.files = self.messages[idx - 1].files.clone();
-            self.messages.remove(idx);
-            self.messages.remove(idx - 1);
-            self.send_message(settings);
+            self.retry_response(settings, idx);
         }
 
         if is_max_height {
@@ -1061,6 +2607,38 @@ impl Chat {
             0.0
         };
 
+        let mut form_result = None;
+        let form_height = match &mut self.template_form {
+            Some(form) => {
+                let resp = ui.vertical(|ui| form.show(ui));
+                form_result = resp.inner;
+                resp.response.rect.height() + 4.0
+            }
+            None => 0.0,
+        };
+        match form_result {
+            Some(true) => {
+                if let Some(form) = self.template_form.take() {
+                    let text = form.render();
+                    self.apply_template(form.template(), text);
+                }
+            }
+            Some(false) => self.template_form = None,
+            None => {}
+        }
+
+        let selector_height = match &mut self.compare {
+            Some(compare) => {
+                let height = ui
+                    .vertical(|ui| compare.show_model_selector(ui, &settings.presets))
+                    .response
+                    .rect
+                    .height();
+                height + 4.0
+            }
+            None => 0.0,
+        };
+
         ui.horizontal_centered(|ui| {
             if ui
                 .add(
@@ -1073,6 +2651,21 @@ impl Chat {
             {
                 action = ChatAction::PickFiles { id: self.id() };
             }
+            let mut compare_mode = self.compare.is_some();
+            if ui
+                .add(
+                    egui::Button::new("⚖")
+                        .min_size(vec2(32.0, 32.0))
+                        .corner_radius(CornerRadius::same(u8::MAX))
+                        .selected(compare_mode),
+                )
+                .on_hover_text_at_pointer("Compare models side by side")
+                .clicked()
+                && !is_generating
+            {
+                compare_mode = !compare_mode;
+                self.compare = compare_mode.then(|| Comparison::new(self.model_picker.selected));
+            }
             ui.with_layout(
                 Layout::left_to_right(Align::Center).with_main_justify(true),
                 |ui| {
@@ -1096,13 +2689,22 @@ impl Chat {
                             .desired_width(f32::INFINITY),
                     );
 
-                    self.chatbox_height = text_edit_resp.rect.height() + images_height;
+                    self.chatbox_height = text_edit_resp.rect.height()
+                        + images_height
+                        + selector_height
+                        + form_height;
 
-                    if !is_generating
+                    if let Some(template) = self.show_slash_popup(ui, &text_edit_resp, settings) {
+                        self.pick_template(template);
+                    } else if !is_generating
                         && text_edit_resp.has_focus()
                         && ui.input(|i| i.key_pressed(Key::Enter) && i.modifiers.is_none())
                     {
-                        self.send_message(settings);
+                        if self.compare.is_some() {
+                            self.send_comparison(settings);
+                        } else {
+                            self.send_message(settings);
+                        }
                     }
                 },
             );
@@ -1115,11 +2717,228 @@ impl Chat {
         action
     }
 
+    /// Lists the templates matching a `/name` typed in the chatbox.
+    /// Returns the template picked by a click, Enter or Tab.
+    fn show_slash_popup(
+        &self,
+        ui: &egui::Ui,
+        chatbox: &egui::Response,
+        settings: &Settings,
+    ) -> Option<PromptTemplate> {
+        const MAX_ITEMS: usize = 8;
+        let query = self.chatbox.strip_prefix('/')?;
+        if !chatbox.has_focus() || query.contains(char::is_whitespace) {
+            return None;
+        }
+        let matches: Vec<&PromptTemplate> = settings
+            .prompt_library
+            .matching(query)
+            .take(MAX_ITEMS)
+            .collect();
+        if matches.is_empty() {
+            return None;
+        }
+
+        let mut picked = ui
+            .input(|i| i.key_pressed(Key::Enter) || i.key_pressed(Key::Tab))
+            .then_some(0);
+        egui::Area::new(Id::new(("slash_popup", self.id)))
+            .order(egui::Order::Foreground)
+            .pivot(egui::Align2::LEFT_BOTTOM)
+            .fixed_pos(chatbox.rect.left_top() - vec2(0.0, 4.0))
+            .show(ui.ctx(), |ui| {
+                Frame::popup(ui.style()).show(ui, |ui| {
+                    for (i, template) in matches.iter().enumerate() {
+                        ui.horizontal(|ui| {
+                            if ui
+                                .selectable_label(i == 0, format!("/{}", template.name))
+                                .clicked()
+                            {
+                                picked = Some(i);
+                            }
+                            ui.weak(template.preview());
+                        });
+                    }
+                });
+            });
+        picked.map(|i| matches[i].clone())
+    }
+
+    /// Inserts a template, asking for its variables first if it has any.
+    fn pick_template(&mut self, template: PromptTemplate) {
+        if template.variables().is_empty() {
+            let text = template.text.clone();
+            self.apply_template(&template, text);
+        } else {
+            self.chatbox.clear();
+            self.template_form = Some(TemplateForm::new(template));
+        }
+    }
+
+    /// Puts the rendered template in the chatbox and applies its model and system prompt.
+    fn apply_template(&mut self, template: &PromptTemplate, text: String) {
+        self.chatbox = text;
+        if let Some(model) = template.model {
+            self.model_picker.selected = model;
+        }
+        if let Some(prompt) = template.system_prompt.as_ref().filter(|p| !p.is_empty()) {
+            self.model_picker.system_prompt = Some(prompt.clone());
+        }
+    }
+
     #[inline]
     pub fn flower_active(&self) -> bool {
         self.flower.is_active()
     }
 
+    /// Whether a response is being generated or waits in the queue.
+    #[inline]
+    pub fn is_busy(&self) -> bool {
+        self.flower_active() || self.queued_at.is_some()
+    }
+
+    /// Number of requests of the chat and its comparison running right now.
+    pub fn running_generations(&self) -> usize {
+        usize::from(self.flower_active()) + self.compare.as_ref().map_or(0, Comparison::running)
+    }
+
+    /// Whether the last response failed.
+    #[inline]
+    pub fn has_error(&self) -> bool {
+        self.messages.last().is_some_and(|m| m.is_error)
+    }
+
+    fn append_response_part(&mut self, part: Part) {
+        if let Some(idx) = self.continued {
+            // Thoughts may still arrive if the model can't hide them, they would
+            // otherwise split the continued answer into a new message
+            match part {
+                Part::Text {
+                    thought: Some(true),
+                    ..
+                } => return,
+                Part::Text { ref text, .. } if idx < self.messages.len() => {
+                    let message = &mut self.messages[idx];
+                    message.content.push_str(text);
+                    push_response_part(&mut message.parts, part);
+                    return;
+                }
+                // Anything else, e.g. a tool call, starts new messages after the answer
+                _ => self.continued = None,
+            }
+        }
+
+        // Safely use unwrap, as we always add
+        // a placeholder message in send_message before running.
+        let current_response_msg = self.messages.last_mut().unwrap();
+        let model = current_response_msg.model;
+        // Parts that are sent back in the history as received
+        let raw_part =
+            matches!(part, Part::Text { .. } | Part::FunctionCall { .. }).then(|| part.clone());
+
+        match part {
+            Part::Text { text, thought, .. } => {
+                if thought.unwrap_or(false) {
+                    // This is a thought
+                    if current_response_msg.is_thought {
+                        // Just append the "thought" text.
+                        current_response_msg.content.push_str(&text);
+                    } else if current_response_msg.is_placeholder() {
+                        // If this is the first part of a "thought", turn our
+                        // placeholder message into a full "thought" message.
+                        current_response_msg.is_thought = true;
+                        current_response_msg.content.push_str(&text);
+                    } else {
+                        // The model thinks again after a tool call.
+                        current_response_msg.finish_generating();
+                        let mut thought_message = Message::assistant(text, model);
+                        thought_message.is_thought = true;
+                        self.messages.push(thought_message);
+                    }
+                } else if current_response_msg.is_block() {
+                    // "Thoughts" (or a tool call) have just ended. Turn off the spinner for them.
+                    current_response_msg.finish_generating();
+
+                    // And create a NEW, separate message for the final answer.
+                    // This will keep the thought block on screen.
+                    self.messages.push(Message::assistant(text, model));
+                } else {
+                    // Either there were no "thoughts", or this is a continuation of the answer.
+                    // Just append the text to the current last message.
+                    current_response_msg.content.push_str(&text);
+                }
+            }
+            Part::FunctionCall { function_call, .. } => {
+                let call = ToolCall {
+                    name: function_call.name,
+                    args: function_call.args,
+                    response: None,
+                    approval: None,
+                };
+                if current_response_msg.is_placeholder() {
+                    current_response_msg.tool_call = Some(call);
+                } else {
+                    current_response_msg.finish_generating();
+                    self.messages.push(Message::tool(call, model));
+                }
+            }
+            Part::ExecutableCode { executable_code } => {
+                let execution = CodeExecution::from_part(&executable_code);
+                if current_response_msg.is_placeholder() {
+                    current_response_msg.code_execution = Some(execution);
+                } else {
+                    current_response_msg.finish_generating();
+                    self.messages
+                        .push(Message::code_execution(execution, model));
+                }
+            }
+            Part::CodeExecutionResult {
+                code_execution_result,
+            } => {
+                if let Some(execution) = self
+                    .messages
+                    .iter_mut()
+                    .rev()
+                    .filter_map(|m| m.code_execution.as_mut())
+                    .find(|e| e.output.is_none())
+                {
+                    execution.set_result(&code_execution_result);
+                }
+            }
+            _ => {} // Handle other parts if needed
+        }
+
+        if let Some(part) = raw_part {
+            // The part always ends up in the last message
+            let message = self.messages.last_mut().unwrap();
+            push_response_part(&mut message.parts, part);
+        }
+    }
+
+    /// Validates the final answer of a finished response against the chat's schema.
+    fn check_structured_output(&mut self, idx: usize) {
+        let structured = self.model_picker.structured_output();
+        if !structured.is_enabled() {
+            return;
+        }
+        let Some(message) = self
+            .messages
+            .iter_mut()
+            .skip(idx)
+            .rev()
+            .find(|m| !m.is_user() && !m.is_block() && !m.is_error)
+        else {
+            return;
+        };
+
+        let errors = structured.validate(&message.content);
+        if !errors.is_empty() {
+            log::warn!("response does not match the schema: {}", errors[0]);
+        }
+        message.parsed_json = crate::structured::parse_json(&message.content).ok();
+        message.schema_errors = Some(errors);
+    }
+
     pub fn poll_flower(&mut self, modal: &mut Modal) {
         let mut last_processed_idx = self.messages.len().saturating_sub(1);
 
@@ -1128,7 +2947,7 @@ impl Chat {
                 last_processed_idx = idx;
 
                 // Clear status message when receiving new parts
-                if let ChatProgress::Part(_) = progress {
+                if let ChatProgress::Part(_) | ChatProgress::GeneratedFile { .. } = progress {
                     if let Some(message) = self.messages.get_mut(idx) {
                         message.status_message = None;
                     }
@@ -1162,111 +2981,99 @@ impl Chat {
                             }
                         }
                     }
-                    ChatProgress::Part(part) => {
-                        match part {
-                            Part::Text { text, thought, .. } => {
-                                // Safely use unwrap, as we always add
-                                // a placeholder message in send_message before running.
-                                let current_response_msg = self.messages.last_mut().unwrap();
-
-                                if thought.unwrap_or(false) {
-                                    // This is a thought
-                                    if !current_response_msg.is_thought {
-                                        // If this is the first part of a "thought", turn our
-                                        // placeholder message into a full "thought" message.
-                                        current_response_msg.is_thought = true;
-                                    }
-                                    // Just append the "thought" text.
-                                    current_response_msg.content.push_str(&text);
-                                } else {
-                                    if current_response_msg.is_thought {
-                                        // "Thoughts" have just ended. Turn off the spinner for them.
-                                        current_response_msg.is_generating = false;
-                                        current_response_msg.generation_time =
-                                            Some(current_response_msg.requested_at.elapsed());
-
-                                        // And create a NEW, separate message for the final answer.
-                                        // This will keep the thought block on screen.
-                                        let model = current_response_msg.model;
-                                        let mut answer_message = Message::assistant(text.into(), model);
-                                        answer_message.is_generating = true; // It has its own spinner.
-                                        self.messages.push(answer_message);
-                                    } else {
-                                        // Either there were no "thoughts", or this is a continuation of the answer.
-                                        // Just append the text to the current last message.
-                                        current_response_msg.content.push_str(&text);
-                                    }
-                                }
+                    ChatProgress::ToolResult { name, response } => {
+                        // Results arrive in the same order as the calls of this response
+                        if let Some(call) = self
+                            .messages
+                            .iter_mut()
+                            .skip(idx)
+                            .filter_map(|m| m.tool_call.as_mut())
+                            .find(|c| c.name == name && c.response.is_none())
+                        {
+                            call.response = Some(response);
+                            call.approval = None;
+                        }
+                    }
+                    ChatProgress::ToolApproval { name, approval } => {
+                        if let Some(call) = self
+                            .messages
+                            .iter_mut()
+                            .skip(idx)
+                            .filter_map(|m| m.tool_call.as_mut())
+                            .find(|c| c.name == name && c.response.is_none())
+                        {
+                            call.approval = Some(approval);
+                        }
+                    }
+                    ChatProgress::GeneratedFile { attachment } => {
+                        let current_response_msg = self.messages.last_mut().unwrap();
+                        if current_response_msg.is_block() {
+                            current_response_msg.finish_generating();
+                            let model = current_response_msg.model;
+                            self.messages.push(Message::assistant(String::new(), model));
+                        }
+                        self.messages.last_mut().unwrap().files.push(attachment);
+                    }
+                    ChatProgress::Sources { sources } => {
+                        let answer_idx = self.answer_idx(idx);
+                        let message = &mut self.messages[answer_idx];
+                        for source in sources {
+                            if !message.sources.contains(&source) {
+                                message.sources.push(source);
                             }
-                            _ => {} // Handle other parts if needed
                         }
                     }
+                    ChatProgress::Candidates { texts } => {
+                        let answer_idx = self.answer_idx(idx);
+                        let message = &mut self.messages[answer_idx];
+                        message.candidates = std::iter::once(message.content.clone())
+                            .chain(texts)
+                            .collect();
+                        message.candidate_index = 0;
+                    }
+                    ChatProgress::Finish {
+                        finish_reason,
+                        safety_ratings,
+                    } => {
+                        let answer_idx = self.answer_idx(idx);
+                        let message = &mut self.messages[answer_idx];
+                        if finish_reason.is_some() {
+                            message.finish_reason = finish_reason;
+                        }
+                        if !safety_ratings.is_empty() {
+                            message.safety_ratings = safety_ratings;
+                        }
+                    }
+                    ChatProgress::ContextSummary { idx, summary } => {
+                        if let Some(message) = self.messages.get_mut(idx) {
+                            message.summary = Some(summary);
+                        }
+                    }
+                    ChatProgress::ContextCache { cache } => self.context_cache = Some(cache),
+                    ChatProgress::ApiKey { name } => {
+                        self.set_key_profile((name != ApiKeyProfile::DEFAULT).then_some(name));
+                    }
+                    ChatProgress::Part(part) => self.append_response_part(part),
                 }
             })
             .finalize(|result| {
+                self.continued = None;
                 if let Ok((idx, _, usage)) = result {
                     if let Some(message) = self.messages.get_mut(idx) {
-                        message.usage = usage;
+                        // A continued response already holds the usage of the first call
+                        message.usage = match (message.usage.take(), usage) {
+                            (Some(first), Some(second)) => Some(merge_usage(first, second)),
+                            (first, second) => second.or(first),
+                        };
                         message.status_message = None;
                     }
+                    self.check_structured_output(idx);
                 } else if let Err(e) = result {
-                    let (idx, msg) = match e {
+                    let (idx, final_msg) = match e {
                         Compact::Panicked(e) => {
                             (self.messages.len() - 1, format!("Tokio task panicked: {e}"))
                         }
-                        Compact::Suppose((idx, e)) => (idx, e),
-                    };
-
-                    // Robust answer extraction
-                    let final_msg = if let Some(start_idx) = msg.find('{') {
-                        let json_candidate = &msg[start_idx..];
-                        let end_idx = json_candidate.rfind('}').map(|i| i + 1).unwrap_or(json_candidate.len());
-                        let json_str = &json_candidate[..end_idx];
-
-                        let unescaped = json_str.replace("\\\"", "\"").replace("\\n", "\n");
-                        let target_json = if serde_json::from_str::<serde_json::Value>(json_str).is_ok() {
-                            json_str
-                        } else {
-                            &unescaped
-                        };
-
-                        if let Ok(json) = serde_json::from_str::<serde_json::Value>(target_json) {
-                            if let Some(err_obj) = json.get("error") {
-                                let code = err_obj.get("code").and_then(|v| v.as_i64()).unwrap_or(0);
-                                let status = err_obj.get("status").and_then(|v| v.as_str()).unwrap_or("UNKNOWN");
-                                let message = err_obj.get("message").and_then(|v| v.as_str()).unwrap_or("No message");
-
-                                match status {
-                                    "RESOURCE_EXHAUSTED" => {
-                                        let retry_info = if message.contains("retry in ") {
-                                            if let Some(pos) = message.find("retry in ") {
-                                                format!("\n\n⏳ **Suggestion:** {}", &message[pos..])
-                                            } else {
-                                                String::new()
-                                            }
-                                        } else {
-                                            String::new()
-                                        };
-
-                                        format!("🛑 **Quota Exhausted (429)**\n\nYou've hit the Gemini API rate limit. Please wait a bit or check your Google AI Studio quota.{}", retry_info)
-                                    },                                                                    "NOT_FOUND" => {
-                                        format!("🚫 **Model Not Found (404)**\n\nThe model you selected is either not found or not supported for this operation. Try choosing a different model.\n\n**Details:** {}", message)
-                                    },
-                                    "PERMISSION_DENIED" => {
-                                        format!("🔒 **Permission Denied (403)**\n\nCheck your API Key and project permissions. Make sure the Key is valid for the selected region.\n\n**Details:** {}", message)
-                                    },
-                                    "INVALID_ARGUMENT" => {
-                                        format!("❌ **Invalid Request (400)**\n\nSomething is wrong with the request parameters.\n\n**Details:** {}", message)
-                                    },
-                                    _ => format!("❗ **Gemini API Error ({})**\n\n**Status:** {}\n**Message:** {}", code, status, message)
-                                }                            } else {
-                                    serde_json::to_string_pretty(&json).unwrap_or_else(|_| msg.clone())
-                                }
-                        } else {
-                            msg.clone()
-                        }
-                    } else {
-                        msg.clone()
+                        Compact::Suppose((idx, e)) => (idx, e.user_message()),
                     };
 
                     if let Some(message) = self.messages.get_mut(idx) {
@@ -1321,6 +3128,9 @@ impl Chat {
             )
         });
         if hovered && primary_clicked {
+            if let Some(compare) = &self.compare {
+                compare.stop();
+            }
             self.stop_generating.store(true, Ordering::SeqCst);
         } else {
             ui.painter().circle(
@@ -1359,7 +3169,13 @@ impl Chat {
         let mut new_speaker: Option<usize> = None;
         let mut any_prepending = false;
         let mut regenerate_response_idx = None;
+        let mut edit_response_idx = None;
+        let mut resend = None;
         let mut message_to_delete_idx: Option<usize> = None;
+        let mut switch_branch = None;
+        let mut use_as_input = None;
+        let mut continue_idx = None;
+        let mut select_candidate = None;
         egui::ScrollArea::vertical()
             .animated(false)
             .id_salt(self.id())
@@ -1405,8 +3221,9 @@ impl Chat {
                     let message = &mut self.messages[index];
                     let prev_speaking = message.is_speaking;
 
-                    if any_prepending && message.is_prepending {
+                    if any_prepending && (message.is_prepending || message.is_editing) {
                         message.is_prepending = false;
+                        message.is_editing = false;
                     }
 
                     ui.push_id(index, |ui| {
@@ -1416,7 +3233,9 @@ impl Chat {
                             #[cfg(feature = "tts")]
                             tts.clone(),
                             index,
+                            false,
                             &mut self.prepend_buf,
+                            &mut self.edit_files,
                         );
                         match action {
                             MessageAction::None => (),
@@ -1426,13 +3245,31 @@ impl Chat {
                             MessageAction::Regenerate(idx) => {
                                 regenerate_response_idx = Some(idx);
                             }
+                            MessageAction::Edit(idx) => {
+                                edit_response_idx = Some(idx);
+                            }
+                            MessageAction::Resend { idx, truncate } => {
+                                resend = Some((idx, truncate));
+                            }
                             MessageAction::Delete(idx) => {
                                 message_to_delete_idx = Some(idx);
                             }
+                            MessageAction::SwitchBranch { idx, branch } => {
+                                switch_branch = Some((idx, branch));
+                            }
+                            MessageAction::UseAsInput(attachment) => {
+                                use_as_input = Some(attachment);
+                            }
+                            MessageAction::Continue(idx) => {
+                                continue_idx = Some(idx);
+                            }
+                            MessageAction::SelectCandidate { idx, candidate } => {
+                                select_candidate = Some((idx, candidate));
+                            }
                         }
                     });
 
-                    any_prepending |= message.is_prepending;
+                    any_prepending |= message.is_prepending || message.is_editing;
 
                     if !prev_speaking && message.is_speaking {
                         new_speaker = Some(index);
@@ -1444,17 +3281,50 @@ impl Chat {
         if let Some(regenerate_idx) = regenerate_response_idx {
             self.regenerate_response(settings, regenerate_idx);
         }
+        if let Some(idx) = edit_response_idx {
+            self.edit_response(idx);
+        }
+        if let Some((idx, truncate)) = resend {
+            if self.is_busy() {
+                log::warn!("Can't resend a message while a response is being generated");
+            } else {
+                self.resend_edited(settings, idx, truncate);
+            }
+        }
         if let Some(idx) = message_to_delete_idx {
-            self.messages.remove(idx);
+            self.remove_message(idx);
+        }
+        if let Some(attachment) = use_as_input {
+            self.attach_files([attachment]);
+        }
+        if let Some((idx, branch)) = switch_branch {
+            if self.is_busy() {
+                log::warn!("Can't switch versions while a response is being generated");
+            } else {
+                self.switch_branch(idx, branch);
+            }
+        }
+        if let Some((idx, candidate)) = select_candidate {
+            let message = &mut self.messages[idx];
+            message.select_candidate(candidate);
+            if message.schema_errors.is_some() {
+                message.schema_errors = Some(
+                    self.model_picker
+                        .structured_output()
+                        .validate(&message.content),
+                );
+            }
+        }
+        if let Some(idx) = continue_idx {
+            if self.is_busy() {
+                log::warn!("Can't continue a response while another one is being generated");
+            } else {
+                self.continue_response(settings, idx);
+            }
         }
         new_speaker
     }
 
-    fn send_text(&mut self, settings: &Settings, text: &str) {
-        self.chatbox = text.to_owned();
-        self.send_message(settings);
-    }
-
     fn show_suggestions(&mut self, ui: &mut egui::Ui, settings: &Settings) {
         egui::ScrollArea::both().auto_shrink(false).show(ui, |ui| {
             widgets::centerer(ui, |ui| {
@@ -1467,38 +3337,33 @@ impl Chat {
                     .max_col_width((avail_width / 2.0).min(200.0))
                     .spacing(vec2(6.0, 6.0))
                     .show(ui, |ui| {
-                        // TODO change it
-                        if widgets::suggestion(ui, "Tell me a fun fact", "about the Roman empire")
+                        let templates = &settings.prompt_library.templates;
+                        let mut picked = None;
+                        for (i, template) in templates.iter().enumerate() {
+                            if widgets::suggestion(
+                                ui,
+                                &format!("/{}", template.name),
+                                &template.preview(),
+                            )
+                            .on_hover_text(&template.text)
                             .clicked()
-                        {
-                            self.send_text(settings, "Tell me a fun fact about the Roman empire");
-                        }
-                        if widgets::suggestion(
-                            ui,
-                            "Show me a code snippet",
-                            "of a web server in Rust",
-                        )
-                        .clicked()
-                        {
-                            self.send_text(
-                                settings,
-                                "Show me a code snippet of a web server in Rust",
-                            );
-                        }
-                        widgets::dummy(ui);
-                        ui.end_row();
-
-                        if widgets::suggestion(ui, "Tell me a joke", "about crabs").clicked() {
-                            self.send_text(settings, "Tell me a joke about crabs");
+                            {
+                                picked = Some(template.clone());
+                            }
+                            if i % 2 == 1 || i + 1 == templates.len() {
+                                widgets::dummy(ui);
+                                ui.end_row();
+                            }
                         }
-                        if widgets::suggestion(ui, "Give me ideas", "for a birthday present")
-                            .clicked()
-                        {
-                            self.send_text(settings, "Give me ideas for a birthday present");
+                        if let Some(template) = picked {
+                            self.pick_template(template);
                         }
-                        widgets::dummy(ui);
-                        ui.end_row();
                     });
+                if settings.prompt_library.templates.is_empty() {
+                    ui.weak("Add prompt templates in ⚙ Settings → Prompt Library");
+                } else {
+                    ui.weak("Type /name in the chatbox to use a template");
+                }
             });
         });
     }
@@ -1515,7 +3380,8 @@ impl Chat {
         let max_height = avail.height() * 0.4 + 24.0;
         let chatbox_panel_height = self.chatbox_height + 24.0;
         let actual_chatbox_panel_height = chatbox_panel_height.min(max_height);
-        let is_generating = self.flower_active();
+        let is_generating = self.is_busy()
+            || self.compare.as_ref().is_some_and(Comparison::is_active);
         let mut action = ChatAction::None;
 
         egui::TopBottomPanel::bottom("chatbox_panel")
@@ -1543,7 +3409,26 @@ impl Chat {
             }))
             .show(ctx, |ui| {
                 // ui.ctx().set_debug_on_hover(true); // TODO DEBUG
-                if self.messages.is_empty() {
+                if let Some(compare) = self.compare.as_mut().filter(|c| c.has_results()) {
+                    if let Some(messages) = compare.show(
+                        ui,
+                        commonmark_cache,
+                        #[cfg(feature = "tts")]
+                        tts.clone(),
+                    ) {
+                        self.messages = messages;
+                    }
+                    if is_generating {
+                        self.stop_generating_button(
+                            ui,
+                            16.0,
+                            pos2(
+                                ui.cursor().max.x - 32.0,
+                                avail.height() - 32.0 - actual_chatbox_panel_height,
+                            ),
+                        );
+                    }
+                } else if self.messages.is_empty() {
                     self.show_suggestions(ui, settings);
                 } else {
                     #[allow(unused_variables)]
@@ -1596,3 +3481,132 @@ impl Chat {
         action
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, thought: bool) -> Part {
+        Part::Text {
+            text: text.to_string(),
+            thought: thought.then_some(true),
+            thought_signature: None,
+        }
+    }
+
+    /// A chat with a question and a response that was cut off.
+    fn cut_off_chat() -> Chat {
+        let model = GeminiModel::default();
+        let mut answer = Message::assistant("The answer is".to_string(), model);
+        answer.finish_generating();
+        let mut chat = Chat::default();
+        chat.messages = vec![
+            Message::user("Question?".to_string(), model, vec![]),
+            answer,
+        ];
+        chat
+    }
+
+    #[test]
+    fn thought_first_stream_splits_the_answer() {
+        let mut chat = Chat::default();
+        chat.messages
+            .push(Message::assistant(String::new(), GeminiModel::default()));
+        chat.append_response_part(text("Thinking", true));
+        chat.append_response_part(text("Answer", false));
+
+        assert_eq!(chat.messages.len(), 2);
+        assert!(chat.messages[0].is_thought);
+        assert_eq!(chat.messages[1].content, "Answer");
+    }
+
+    #[test]
+    fn continuation_extends_the_same_message() {
+        let mut chat = cut_off_chat();
+        chat.continued = Some(1);
+        chat.append_response_part(text("Let me think", true));
+        chat.append_response_part(text(" 42", false));
+        chat.append_response_part(text(".", false));
+
+        assert_eq!(chat.messages.len(), 2);
+        assert_eq!(chat.messages[1].content, "The answer is 42.");
+        assert!(!chat.messages[1].is_thought);
+        assert!(chat.messages[1]
+            .parts
+            .iter()
+            .all(|p| matches!(p, Part::Text { thought: None, .. })));
+    }
+
+    /// Settings with two API keys and key rotation on.
+    fn rotating_settings(auth_method: widgets::AuthMethod) -> Settings {
+        let mut settings = Settings {
+            api_key: "first-key".to_string(),
+            rotate_keys: true,
+            auth_method,
+            ..Default::default()
+        };
+        settings.api_keys.push(ApiKeyProfile {
+            name: "Billed".to_string(),
+            key: "second-key".to_string(),
+            ..Default::default()
+        });
+        settings
+    }
+
+    #[test]
+    fn code_assist_requests_never_rotate_keys() {
+        let model_picker = ModelPicker::default();
+        let settings = rotating_settings(widgets::AuthMethod::ApiKey);
+        let current = settings.key_profile(None);
+        let rotation = KeyRotation::for_request(&settings, current.as_ref(), &model_picker);
+        assert_eq!(
+            rotation.map(|r| r.next.into_iter().map(|p| p.name).collect::<Vec<_>>()),
+            Some(vec!["Billed".to_string()])
+        );
+
+        let settings = rotating_settings(widgets::AuthMethod::CodeAssist);
+        let current = settings.key_profile(None);
+        assert!(KeyRotation::for_request(&settings, current.as_ref(), &model_picker).is_none());
+
+        // Even with a rotation, a quota error keeps the request on the signed-in account
+        let mut client =
+            CompletionClient::CodeAssist(gemini_code_assist_adapter::CodeAssistClient::new(
+                "token".to_string(),
+                "project".to_string(),
+            ));
+        let mut options = CompletionOptions {
+            use_streaming: false,
+            public_file_upload: false,
+            generation_config: GenerationConfig::default(),
+            system_prompt: None,
+            tools: Vec::new(),
+            safety_settings: Vec::new(),
+            retry: RetryPolicy::default(),
+            cache_ttl: None,
+            cache: None,
+            cached_content: None,
+            key_rotation: Some(KeyRotation::new(
+                &settings,
+                ApiKeyProfile::DEFAULT,
+                model_picker,
+            )),
+            tool_scope: ToolScope::default(),
+        };
+        let quota_error = CompletionError::Api(crate::api_error::ApiError {
+            code: Some(429),
+            status: Some("RESOURCE_EXHAUSTED".to_string()),
+            ..Default::default()
+        });
+        let flower = CompletionFlower::new(1);
+        let switched = switch_key(
+            &quota_error,
+            &mut client,
+            &mut [],
+            &mut options,
+            &flower.handle(),
+            0,
+        );
+        assert!(!switched);
+        assert!(matches!(client, CompletionClient::CodeAssist(_)));
+    }
+}
@@ -1,4 +1,5 @@
 use crate::chat::{ChatProgress, CompletionFlowerHandle, Message, MessageRole};
+use crate::context::ContextMark;
 use crate::file_handler::{convert_file_to_part, Attachment, AttachmentState, FileResult};
 use anyhow::Result;
 use gemini_rust::{Content, FileData, Gemini, Part, Role};
@@ -13,35 +14,105 @@ pub async fn build_history(
     let mut history: Vec<Content> = Vec::new();
     let mut parts_buffer: Vec<Part> = Vec::new();
     let mut active_role: Option<Role> = None;
+    // Function responses are sent in the user turn right after the model turn that called them
+    let mut pending_responses: Vec<Part> = Vec::new();
+    // Summarized messages are replaced by the summary stored on the last of them
+    let last_summarized = messages
+        .iter()
+        .rposition(|m| m.context == ContextMark::Summarized);
 
     // Process main messages
     for (msg_idx, message) in messages.iter().enumerate() {
-        if message.is_thought || (message.content.is_empty() && message.files.is_empty()) {
+        match message.context {
+            ContextMark::Excluded => continue,
+            ContextMark::Summarized => {
+                let summary = message.summary.as_ref();
+                if let Some(summary) = summary.filter(|_| last_summarized == Some(msg_idx)) {
+                    switch_role(
+                        &mut history,
+                        &mut parts_buffer,
+                        &mut active_role,
+                        Role::User,
+                    );
+                    parts_buffer.push(Part::Text {
+                        text: format!("Summary of the earlier conversation:\n\n{summary}"),
+                        thought: None,
+                        thought_signature: None,
+                    });
+                }
+                continue;
+            }
+            ContextMark::Included | ContextMark::FilesExcluded => {}
+        }
+
+        if let Some(call) = &message.tool_call {
+            switch_role(
+                &mut history,
+                &mut parts_buffer,
+                &mut active_role,
+                Role::Model,
+            );
+            // The raw part keeps the thought signature of the call
+            let part = message.raw_parts().and_then(|parts| parts.first());
+            parts_buffer.push(part.cloned().unwrap_or_else(|| call.call_part()));
+            if let Some(response) = call.response_part() {
+                pending_responses.push(response);
+            }
+            continue;
+        }
+
+        if let Some(execution) = &message.code_execution {
+            switch_role(
+                &mut history,
+                &mut parts_buffer,
+                &mut active_role,
+                Role::Model,
+            );
+            parts_buffer.push(Part::Text {
+                text: execution.to_markdown(),
+                thought: None,
+                thought_signature: None,
+            });
+            continue;
+        }
+
+        // Model turns are sent back verbatim, with their thoughts and thought signatures
+        let raw_parts = message.raw_parts().filter(|_| !message.is_user());
+        if message.is_error
+            || (message.is_thought && raw_parts.is_none())
+            || (raw_parts.is_none() && message.content.is_empty() && message.files.is_empty())
+        {
             continue;
         }
 
+        if !pending_responses.is_empty() {
+            switch_role(
+                &mut history,
+                &mut parts_buffer,
+                &mut active_role,
+                Role::User,
+            );
+            parts_buffer.append(&mut pending_responses);
+        }
+
         let message_role = match message.role {
             MessageRole::User => Role::User,
             MessageRole::Assistant => Role::Model,
         };
+        switch_role(
+            &mut history,
+            &mut parts_buffer,
+            &mut active_role,
+            message_role,
+        );
 
-        if let Some(current_role) = &active_role {
-            if *current_role != message_role {
-                if !parts_buffer.is_empty() {
-                    history.push(Content {
-                        parts: Some(std::mem::take(&mut parts_buffer)),
-                        role: Some(current_role.clone()),
-                    });
-                }
-                active_role = Some(message_role);
-            }
-        } else {
-            active_role = Some(message_role);
-        }
-
+        let files = match message.context {
+            ContextMark::FilesExcluded => &[][..],
+            _ => &message.files,
+        };
         process_attachments(
             gemini,
-            &message.files,
+            files,
             &mut parts_buffer,
             public_file_upload,
             status_channel,
@@ -49,7 +120,9 @@ pub async fn build_history(
         )
         .await;
 
-        if !message.content.is_empty() {
+        if let Some(parts) = raw_parts {
+            parts_buffer.extend(parts.iter().cloned());
+        } else if !message.content.is_empty() {
             parts_buffer.push(Part::Text {
                 text: message.content.clone(),
                 thought: None,
@@ -58,6 +131,16 @@ pub async fn build_history(
         }
     }
 
+    if !pending_responses.is_empty() {
+        switch_role(
+            &mut history,
+            &mut parts_buffer,
+            &mut active_role,
+            Role::User,
+        );
+        parts_buffer.append(&mut pending_responses);
+    }
+
     if !parts_buffer.is_empty() {
         if let Some(role) = active_role {
             history.push(Content {
@@ -109,6 +192,28 @@ pub async fn build_history(
     Ok(history)
 }
 
+/// Flushes the buffered parts into `history` when the speaker changes.
+fn switch_role(
+    history: &mut Vec<Content>,
+    parts_buffer: &mut Vec<Part>,
+    active_role: &mut Option<Role>,
+    role: Role,
+) {
+    if active_role.as_ref() == Some(&role) {
+        return;
+    }
+
+    if let Some(current_role) = active_role.take() {
+        if !parts_buffer.is_empty() {
+            history.push(Content {
+                parts: Some(std::mem::take(parts_buffer)),
+                role: Some(current_role),
+            });
+        }
+    }
+    *active_role = Some(role);
+}
+
 async fn process_attachments(
     gemini: &Gemini,
     files: &[Attachment],
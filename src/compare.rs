@@ -0,0 +1,319 @@
+#[cfg(feature = "tts")]
+use crate::sessions::SharedTts;
+
+use crate::{
+    chat::{Chat, Message},
+    file_handler::Attachment,
+    presets::Presets,
+    widgets::{GeminiModel, Settings},
+};
+use eframe::egui::{self, Frame};
+use egui_commonmark::CommonMarkCache;
+use egui_modal::Modal;
+use std::{
+    fmt,
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+/// One prompt sent to several models at once, with the responses shown side by side.
+#[derive(Default)]
+pub struct Comparison {
+    /// Models and presets the next prompt is sent to.
+    pub contenders: Vec<Contender>,
+    columns: Vec<Column>,
+    /// Index of the compared prompt in the messages of every column.
+    prompt_idx: usize,
+}
+
+/// What a compared response is generated with.
+#[derive(Clone, PartialEq, Eq)]
+pub enum Contender {
+    /// A model, with the settings and system prompt of the chat.
+    Model(GeminiModel),
+    /// The model, settings and system prompt of a preset, by name.
+    Preset(String),
+}
+
+impl fmt::Display for Contender {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Model(model) => write!(f, "{model}"),
+            Self::Preset(name) => write!(f, "⭐ {name}"),
+        }
+    }
+}
+
+/// The response of one model. Each column is a throwaway chat holding a copy of the
+/// history, so it can be promoted into the real chat as is.
+struct Column {
+    label: String,
+    chat: Chat,
+    started: Instant,
+    elapsed: Option<Duration>,
+}
+
+impl Comparison {
+    pub fn new(model: GeminiModel) -> Self {
+        Self {
+            contenders: vec![Contender::Model(model)],
+            ..Default::default()
+        }
+    }
+
+    #[inline]
+    pub fn has_results(&self) -> bool {
+        !self.columns.is_empty()
+    }
+
+    /// Whether any model is still generating or waits in the queue.
+    pub fn is_active(&self) -> bool {
+        self.columns.iter().any(|c| c.chat.is_busy())
+    }
+
+    /// Number of models generating right now, queued ones excluded.
+    pub fn running(&self) -> usize {
+        self.columns
+            .iter()
+            .filter(|c| c.chat.flower_active())
+            .count()
+    }
+
+    /// Columns waiting for a free generation slot, with the time they were queued.
+    pub fn queued(&self) -> impl Iterator<Item = (usize, Instant)> + '_ {
+        self.columns
+            .iter()
+            .enumerate()
+            .filter_map(|(i, c)| Some((i, c.chat.queued_at?)))
+    }
+
+    /// The chat of a column, to start it when its turn in the queue comes.
+    pub fn column_mut(&mut self, idx: usize) -> Option<&mut Chat> {
+        self.columns.get_mut(idx).map(|c| &mut c.chat)
+    }
+
+    /// Sends the prompt to every selected model, on top of the history of `chat`.
+    /// With a concurrency limit, the columns wait in the same queue as the chats.
+    pub fn start(
+        &mut self,
+        chat: &Chat,
+        prompt: String,
+        files: Vec<Attachment>,
+        settings: &Settings,
+    ) {
+        self.prompt_idx = chat.messages.len();
+        self.columns = self
+            .contenders
+            .iter()
+            .filter_map(|contender| {
+                let model_picker = match contender {
+                    Contender::Model(model) => {
+                        let mut model_picker = chat.model_picker.clone();
+                        model_picker.selected = *model;
+                        model_picker
+                    }
+                    Contender::Preset(name) => {
+                        let Some(preset) = settings.presets.get(name) else {
+                            log::warn!("the preset `{name}` no longer exists");
+                            return None;
+                        };
+                        preset.model_picker.clone()
+                    }
+                };
+                Some((contender.to_string(), model_picker))
+            })
+            .enumerate()
+            .map(|(i, (label, model_picker))| {
+                let model = model_picker.selected;
+                let mut column = Chat::new(i, model_picker);
+                column.messages = chat.messages.clone();
+                column.key_profile = chat.key_profile.clone();
+                column
+                    .messages
+                    .push(Message::user(prompt.clone(), model, files.clone()));
+                column
+                    .messages
+                    .push(Message::assistant(String::new(), model));
+                column.spawn_completion(settings);
+
+                Column {
+                    label,
+                    chat: column,
+                    started: Instant::now(),
+                    elapsed: None,
+                }
+            })
+            .collect();
+    }
+
+    pub fn poll(&mut self, modal: &mut Modal) {
+        for column in &mut self.columns {
+            if column.chat.queued_at.is_some() {
+                // The generation time starts when the column leaves the queue
+                column.started = Instant::now();
+            } else if column.chat.flower_active() {
+                column.chat.poll_flower(modal);
+                if !column.chat.flower_active() {
+                    column.elapsed = Some(column.started.elapsed());
+                }
+            }
+        }
+    }
+
+    /// Cancels every running generation.
+    pub fn stop(&self) {
+        for column in &self.columns {
+            if column.chat.is_busy() {
+                column.chat.stop_generating.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Adds `contender` if `selected`, removes it otherwise.
+    fn toggle(&mut self, contender: Contender, selected: bool) {
+        if selected {
+            self.contenders.push(contender);
+        } else {
+            self.contenders.retain(|c| *c != contender);
+        }
+    }
+
+    pub fn show_model_selector(&mut self, ui: &mut egui::Ui, presets: &Presets) {
+        ui.horizontal_wrapped(|ui| {
+            ui.label("Compare:");
+            let mut removed = None;
+            for contender in &self.contenders {
+                if ui
+                    .selectable_label(true, contender.to_string())
+                    .on_hover_text("Click to remove")
+                    .clicked()
+                {
+                    removed = Some(contender.clone());
+                }
+            }
+            if let Some(contender) = removed {
+                self.toggle(contender, false);
+            }
+            ui.menu_button("➕", |ui| {
+                if !presets.presets.is_empty() {
+                    ui.menu_button("Presets", |ui| {
+                        for preset in &presets.presets {
+                            let contender = Contender::Preset(preset.name.clone());
+                            let mut selected = self.contenders.contains(&contender);
+                            if ui
+                                .checkbox(&mut selected, &preset.name)
+                                .on_hover_text(preset.model_picker.selected.to_string())
+                                .changed()
+                            {
+                                self.toggle(contender, selected);
+                            }
+                        }
+                    });
+                    ui.separator();
+                }
+                crate::models::show_grouped(ui, |ui, info| {
+                    let contender = Contender::Model(info.model());
+                    let mut selected = self.contenders.contains(&contender);
+                    let resp = ui.checkbox(&mut selected, info.label());
+                    if resp.changed() {
+                        self.toggle(contender, selected);
+                    }
+                    resp
+                });
+            })
+            .response
+            .on_hover_text("Add models or presets to compare");
+        });
+    }
+
+    /// Shows the responses in columns.
+    /// Returns the messages of the response that should become the chat history.
+    pub fn show(
+        &mut self,
+        ui: &mut egui::Ui,
+        commonmark_cache: &mut CommonMarkCache,
+        #[cfg(feature = "tts")] tts: SharedTts,
+    ) -> Option<Vec<Message>> {
+        let mut promoted = None;
+        let prompt_idx = self.prompt_idx;
+
+        if let Some(prompt) = self
+            .columns
+            .first()
+            .and_then(|c| c.chat.messages.get(prompt_idx))
+        {
+            ui.label(egui::RichText::new(&prompt.content).strong());
+            ui.separator();
+        }
+
+        ui.columns(self.columns.len().max(1), |uis| {
+            for (i, (ui, column)) in uis.iter_mut().zip(&mut self.columns).enumerate() {
+                ui.push_id(i, |ui| {
+                    let generating = column.chat.is_busy();
+                    Frame::group(ui.style()).show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.strong(&column.label)
+                                .on_hover_text(column.chat.model_picker.selected.to_string());
+                            let elapsed =
+                                column.elapsed.unwrap_or_else(|| column.started.elapsed());
+                            ui.weak(format!("{:.1}s", elapsed.as_secs_f64()))
+                                .on_hover_text("Generation time");
+                            if let Some(usage) = column.chat.messages[prompt_idx + 1..]
+                                .iter()
+                                .find_map(|m| m.usage.as_ref())
+                            {
+                                ui.weak(format!(
+                                    "In: {} / Out: {} / Total: {}",
+                                    usage.prompt_token_count.unwrap_or(0),
+                                    usage.candidates_token_count.unwrap_or(0),
+                                    usage.total_token_count.unwrap_or(0),
+                                ));
+                            }
+                        });
+
+                        if ui
+                            .add_enabled(!generating, egui::Button::new("⬆ Use this response"))
+                            .on_hover_text("Continue the chat with this response")
+                            .clicked()
+                        {
+                            promoted = Some(std::mem::take(&mut column.chat.messages));
+                        }
+                    });
+
+                    egui::ScrollArea::vertical()
+                        .id_salt(("compare_column", i))
+                        .auto_shrink(false)
+                        .show(ui, |ui| {
+                            let Chat {
+                                messages,
+                                prepend_buf,
+                                edit_files,
+                                ..
+                            } = &mut column.chat;
+                            let len = messages.len();
+                            for idx in prompt_idx + 1..len {
+                                ui.push_id(idx, |ui| {
+                                    // Versions, edits and retries only make sense in the real chat
+                                    messages[idx].show(
+                                        ui,
+                                        commonmark_cache,
+                                        #[cfg(feature = "tts")]
+                                        tts.clone(),
+                                        idx,
+                                        true,
+                                        prepend_buf,
+                                        edit_files,
+                                    );
+                                });
+                            }
+                        });
+                });
+            }
+        });
+
+        if promoted.is_some() {
+            self.columns.clear();
+        }
+        promoted
+    }
+}
@@ -0,0 +1,188 @@
+use crate::widgets::{GeminiModel, Settings};
+use serde::Deserialize;
+use std::{collections::BTreeMap, path::PathBuf};
+
+const CONFIG_FILE: &str = "config.toml";
+
+/// Settings that can be set in `config.toml`. Every field is optional.
+#[derive(Default, Deserialize)]
+#[serde(default)]
+struct FileConfig {
+    api_key: Option<String>,
+    proxy: Option<String>,
+    model: Option<String>,
+    base_url: Option<String>,
+    use_streaming: Option<bool>,
+    public_file_upload: Option<bool>,
+    max_concurrent_generations: Option<usize>,
+    /// Keys that are not settings, e.g. misspelled ones. They are reported and ignored.
+    #[serde(flatten)]
+    unknown: BTreeMap<String, toml::Value>,
+}
+
+/// The config directory, e.g. `~/.config/GeminiD` on Linux.
+pub fn config_dir() -> Option<PathBuf> {
+    directories::ProjectDirs::from("", "", crate::TITLE).map(|dirs| dirs.config_dir().into())
+}
+
+impl FileConfig {
+    /// `config.toml` in the config directory.
+    fn path() -> Option<PathBuf> {
+        config_dir().map(|dir| dir.join(CONFIG_FILE))
+    }
+
+    fn load() -> Self {
+        let Some(path) = Self::path().filter(|p| p.exists()) else {
+            return Self::default();
+        };
+        log::info!("reading managed settings from `{}`", path.display());
+        let config = std::fs::read_to_string(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|text| Ok(toml::from_str::<Self>(&text)?))
+            .unwrap_or_else(|e| {
+                log::error!("failed to read `{}`: {e}", path.display());
+                Self::default()
+            });
+        if !config.unknown.is_empty() {
+            let keys: Vec<&str> = config.unknown.keys().map(String::as_str).collect();
+            log::error!(
+                "ignoring unknown settings in `{}`: {}",
+                path.display(),
+                keys.join(", ")
+            );
+        }
+        config
+    }
+}
+
+/// A setting that can be managed by the environment or the config file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Field {
+    ApiKey,
+    Proxy,
+    Model,
+    BaseUrl,
+    UseStreaming,
+    PublicFileUpload,
+    MaxConcurrentGenerations,
+}
+
+/// A value set outside the app, with the variable or file it comes from.
+#[derive(Clone)]
+struct Managed<T> {
+    value: T,
+    source: &'static str,
+}
+
+/// Settings managed by environment variables and `config.toml`. They are applied
+/// over the stored settings at startup and locked in the settings panel.
+/// Environment variables take precedence over the file.
+#[derive(Default, Clone)]
+pub struct Overrides {
+    api_key: Option<Managed<String>>,
+    proxy: Option<Managed<String>>,
+    model: Option<Managed<GeminiModel>>,
+    base_url: Option<Managed<String>>,
+    use_streaming: Option<Managed<bool>>,
+    public_file_upload: Option<Managed<bool>>,
+    max_concurrent_generations: Option<Managed<usize>>,
+}
+
+/// The first of the environment variables that is set.
+fn env(names: &[&'static str]) -> Option<Managed<String>> {
+    names.iter().find_map(|&name| {
+        let value = std::env::var(name).ok()?;
+        let value = value.trim();
+        (!value.is_empty()).then(|| Managed {
+            value: value.to_string(),
+            source: name,
+        })
+    })
+}
+
+fn file<T>(value: Option<T>) -> Option<Managed<T>> {
+    value.map(|value| Managed {
+        value,
+        source: CONFIG_FILE,
+    })
+}
+
+fn parse_model(Managed { value, source }: Managed<String>) -> Option<Managed<GeminiModel>> {
+    let id = value.trim().trim_start_matches("models/");
+    if !crate::models::is_valid_id(id) {
+        log::error!("ignoring the model set by {source}: `{id}` is not a valid model ID");
+        return None;
+    }
+    Some(Managed {
+        value: GeminiModel::new(id),
+        source,
+    })
+}
+
+impl Overrides {
+    pub fn load() -> Self {
+        let config = FileConfig::load();
+        Self {
+            api_key: env(&["GEMINI_API_KEY", "GOOGLE_API_KEY"]).or(file(config.api_key)),
+            proxy: env(&["GEMINI_PROXY"]).or(file(config.proxy)),
+            model: env(&["GEMINI_MODEL"])
+                .or(file(config.model))
+                .and_then(parse_model),
+            base_url: env(&["GEMINI_BASE_URL"]).or(file(config.base_url)),
+            use_streaming: file(config.use_streaming),
+            public_file_upload: file(config.public_file_upload),
+            max_concurrent_generations: file(config.max_concurrent_generations),
+        }
+    }
+
+    /// Sets the managed values, and keeps the overrides to lock them in the panel.
+    pub fn apply(self, settings: &mut Settings) {
+        if let Some(api_key) = &self.api_key {
+            settings.api_key = api_key.value.clone();
+        }
+        if let Some(proxy) = &self.proxy {
+            settings.proxy_path = Some(proxy.value.clone());
+        }
+        if let Some(model) = &self.model {
+            settings.model_picker.selected = model.value;
+        }
+        if let Some(base_url) = &self.base_url {
+            settings.base_url = Some(base_url.value.clone());
+        }
+        if let Some(use_streaming) = &self.use_streaming {
+            settings.use_streaming = use_streaming.value;
+        }
+        if let Some(public_file_upload) = &self.public_file_upload {
+            settings.public_file_upload = public_file_upload.value;
+        }
+        if let Some(limit) = &self.max_concurrent_generations {
+            settings.max_concurrent_generations = Some(limit.value.max(1));
+        }
+        settings.overrides = self;
+    }
+
+    /// Where the value of `field` comes from, `None` if the user can change it.
+    pub fn source(&self, field: Field) -> Option<&'static str> {
+        match field {
+            Field::ApiKey => self.api_key.as_ref().map(|m| m.source),
+            Field::Proxy => self.proxy.as_ref().map(|m| m.source),
+            Field::Model => self.model.as_ref().map(|m| m.source),
+            Field::BaseUrl => self.base_url.as_ref().map(|m| m.source),
+            Field::UseStreaming => self.use_streaming.as_ref().map(|m| m.source),
+            Field::PublicFileUpload => self.public_file_upload.as_ref().map(|m| m.source),
+            Field::MaxConcurrentGenerations => {
+                self.max_concurrent_generations.as_ref().map(|m| m.source)
+            }
+        }
+    }
+
+    /// The managed default model, if any.
+    pub fn model(&self) -> Option<GeminiModel> {
+        self.model.as_ref().map(|m| m.value)
+    }
+
+    #[inline]
+    pub fn is_locked(&self, field: Field) -> bool {
+        self.source(field).is_some()
+    }
+}
@@ -0,0 +1,345 @@
+use crate::{
+    chat::{Message, MessageRole},
+    file_handler::Attachment,
+    widgets::{help, toggle},
+};
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+
+/// What to do with the older part of a chat once it gets too long.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextStrategy {
+    #[default]
+    KeepLastTurns,
+    DropAttachments,
+    Summarize,
+}
+
+impl ContextStrategy {
+    pub const ALL: [Self; 3] = [Self::KeepLastTurns, Self::DropAttachments, Self::Summarize];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::KeepLastTurns => "Keep the last turns",
+            Self::DropAttachments => "Drop older attachments",
+            Self::Summarize => "Summarize older turns",
+        }
+    }
+}
+
+/// Whether (and how) a message is sent to the model.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ContextMark {
+    #[default]
+    Included,
+    Excluded,
+    /// The text is sent, but not the attachments.
+    FilesExcluded,
+    /// Replaced by the summary stored on the last summarized message.
+    Summarized,
+}
+
+/// Share of the model's input limit above which the policy kicks in, in percent.
+const DEFAULT_THRESHOLD_PERCENT: u32 = 80;
+
+/// Characters per token used to estimate text the API hasn't counted yet.
+pub const CHARS_PER_TOKEN: u64 = 4;
+
+/// Per-chat policy applied when the history approaches the input limit.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ContextPolicy {
+    pub enabled: bool,
+    pub strategy: ContextStrategy,
+    /// Number of recent turns (a user message and its response) that are always sent as is.
+    pub keep_turns: usize,
+    /// Token count above which the policy kicks in, instead of a share of the model's
+    /// input limit.
+    pub custom_token_limit: Option<u32>,
+}
+
+impl Default for ContextPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            strategy: ContextStrategy::default(),
+            keep_turns: 10,
+            custom_token_limit: None,
+        }
+    }
+}
+
+impl ContextPolicy {
+    /// Token count of the history above which the policy kicks in.
+    pub fn threshold(&self, input_token_limit: u32) -> u32 {
+        self.custom_token_limit.unwrap_or_else(|| {
+            (u64::from(input_token_limit) * u64::from(DEFAULT_THRESHOLD_PERCENT) / 100) as u32
+        })
+    }
+
+    /// Marks the messages that should be left out of the next request, given the
+    /// model's input limit and the size of the outgoing history (see [`estimate_tokens`]).
+    ///
+    /// Marks are sticky: once the history got too long, the older messages stay
+    /// out of the context, otherwise the next request would overflow again.
+    pub fn apply(&self, messages: &mut [Message], input_token_limit: u32, token_count: u32) {
+        if !self.enabled {
+            for message in messages.iter_mut() {
+                message.context = ContextMark::Included;
+            }
+            return;
+        }
+        if token_count < self.threshold(input_token_limit) {
+            return;
+        }
+
+        // Start of the oldest turn that is kept as is
+        let Some(cutoff) = messages
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, m)| m.role == MessageRole::User)
+            .nth(self.keep_turns.max(1) - 1)
+            .map(|(i, _)| i)
+        else {
+            return;
+        };
+
+        for message in &mut messages[..cutoff] {
+            if message.context == ContextMark::Summarized {
+                continue;
+            }
+            message.context = match self.strategy {
+                ContextStrategy::KeepLastTurns => ContextMark::Excluded,
+                ContextStrategy::DropAttachments if message.files.is_empty() => continue,
+                ContextStrategy::DropAttachments => ContextMark::FilesExcluded,
+                ContextStrategy::Summarize => ContextMark::Summarized,
+            };
+        }
+        if self.strategy == ContextStrategy::Summarize {
+            log::info!("summarizing {cutoff} message(s) to fit the context window");
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui, input_token_limit: u32) {
+        ui.horizontal(|ui| {
+            ui.add(toggle(&mut self.enabled));
+            help(ui, "Shorten the history sent to the model once the chat gets too long. Affected messages are marked in the chat", |ui| {
+                ui.label("Manage context window");
+            });
+        });
+
+        ui.add_enabled_ui(self.enabled, |ui| {
+            egui::ComboBox::from_id_salt("context_strategy")
+                .selected_text(self.strategy.label())
+                .show_ui(ui, |ui| {
+                    for strategy in ContextStrategy::ALL {
+                        ui.selectable_value(&mut self.strategy, strategy, strategy.label());
+                    }
+                });
+            let mut custom = self.custom_token_limit.is_some();
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut custom, "Custom threshold");
+                if !custom {
+                    ui.weak(format!(
+                        "{DEFAULT_THRESHOLD_PERCENT}% of the input limit, {} tokens",
+                        self.threshold(input_token_limit)
+                    ));
+                }
+            });
+            if !custom {
+                self.custom_token_limit = None;
+            } else if self.custom_token_limit.is_none() {
+                self.custom_token_limit = Some(self.threshold(input_token_limit));
+            }
+            if let Some(limit) = &mut self.custom_token_limit {
+                ui.horizontal(|ui| {
+                    ui.label("Above");
+                    ui.add(
+                        egui::DragValue::new(limit)
+                            .speed(1000.0)
+                            .range(1000..=input_token_limit.max(1000)),
+                    );
+                    ui.label("tokens");
+                });
+            }
+            ui.horizontal(|ui| {
+                ui.label("Keep the last");
+                ui.add(egui::DragValue::new(&mut self.keep_turns).range(1..=100));
+                ui.label("turns");
+            });
+        });
+    }
+}
+
+/// Size of the history sent with the next request, including the new prompt.
+///
+/// The usage reported with the last response covers the history up to it; the
+/// messages after it, and chats without usage, are estimated from their size.
+pub fn estimate_tokens(messages: &[Message]) -> u32 {
+    let counted = messages
+        .iter()
+        .rposition(|m| m.usage.is_some())
+        .and_then(|i| {
+            let usage = messages[i].usage.as_ref()?;
+            let tokens =
+                usage.prompt_token_count.unwrap_or(0) + usage.candidates_token_count.unwrap_or(0);
+            Some((i + 1, u64::try_from(tokens).unwrap_or(0)))
+        });
+    let (start, known) = counted.unwrap_or((0, 0));
+
+    let estimate: u64 = messages[start..]
+        .iter()
+        .map(|message| {
+            let text = message.content.len() as u64 / CHARS_PER_TOKEN;
+            match message.context {
+                ContextMark::Excluded => 0,
+                ContextMark::Summarized => {
+                    message.summary.as_ref().map_or(0, String::len) as u64 / CHARS_PER_TOKEN
+                }
+                ContextMark::FilesExcluded => text,
+                ContextMark::Included => {
+                    text + message.files.iter().map(estimate_file_tokens).sum::<u64>()
+                }
+            }
+        })
+        .sum();
+    u32::try_from(known + estimate).unwrap_or(u32::MAX)
+}
+
+/// Rough token count of an attachment, from its type and size.
+pub fn estimate_file_tokens(attachment: &Attachment) -> u64 {
+    let size = std::fs::metadata(&attachment.path).map_or(0, |m| m.len());
+    match attachment.mime.split('/').next().unwrap_or_default() {
+        "image" => 258,
+        // 32 tokens per second, about 16 kB of compressed audio
+        "audio" => size / 500,
+        // 263 tokens per second, about 1 MB of video
+        "video" => size / 4000,
+        // 258 tokens per page, about 50 kB
+        _ if attachment.mime == "application/pdf" => size / 200,
+        _ => size / CHARS_PER_TOKEN,
+    }
+}
+
+/// Index of the last message whose summary still has to be generated, if any.
+pub fn pending_summary(messages: &[Message]) -> Option<usize> {
+    let last = messages
+        .iter()
+        .rposition(|m| m.context == ContextMark::Summarized)?;
+    messages[last].summary.is_none().then_some(last)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::widgets::GeminiModel;
+
+    /// `turns` user messages with an image each, followed by their responses.
+    fn history(turns: usize) -> Vec<Message> {
+        (0..turns)
+            .flat_map(|i| {
+                let image = Attachment::from_path(format!("image{i}.png").into());
+                [
+                    Message::user(format!("question {i}"), GeminiModel::default(), vec![image]),
+                    Message::assistant(format!("answer {i}"), GeminiModel::default()),
+                ]
+            })
+            .collect()
+    }
+
+    fn policy(strategy: ContextStrategy) -> ContextPolicy {
+        ContextPolicy {
+            enabled: true,
+            strategy,
+            keep_turns: 2,
+            custom_token_limit: None,
+        }
+    }
+
+    fn marks(messages: &[Message]) -> Vec<ContextMark> {
+        messages.iter().map(|m| m.context).collect()
+    }
+
+    #[test]
+    fn threshold_from_input_limit() {
+        let mut policy = policy(ContextStrategy::KeepLastTurns);
+        assert_eq!(policy.threshold(1_000_000), 800_000);
+        policy.custom_token_limit = Some(5000);
+        assert_eq!(policy.threshold(1_000_000), 5000);
+    }
+
+    #[test]
+    fn below_threshold_keeps_everything() {
+        let mut messages = history(4);
+        policy(ContextStrategy::KeepLastTurns).apply(&mut messages, 1000, 799);
+        assert!(marks(&messages).iter().all(|&m| m == ContextMark::Included));
+    }
+
+    #[test]
+    fn keeps_last_turns() {
+        let mut messages = history(4);
+        policy(ContextStrategy::KeepLastTurns).apply(&mut messages, 1000, 800);
+        use ContextMark::{Excluded, Included};
+        assert_eq!(
+            marks(&messages),
+            [Excluded, Excluded, Excluded, Excluded, Included, Included, Included, Included]
+        );
+    }
+
+    #[test]
+    fn drops_attachments() {
+        let mut messages = history(3);
+        policy(ContextStrategy::DropAttachments).apply(&mut messages, 1000, 900);
+        use ContextMark::{FilesExcluded, Included};
+        assert_eq!(
+            marks(&messages),
+            [
+                FilesExcluded,
+                Included,
+                Included,
+                Included,
+                Included,
+                Included
+            ]
+        );
+    }
+
+    #[test]
+    fn summarized_marks_are_sticky() {
+        let mut messages = history(3);
+        let policy = policy(ContextStrategy::Summarize);
+        policy.apply(&mut messages, 1000, 900);
+        assert_eq!(pending_summary(&messages), Some(1));
+
+        // Under the threshold again, the summarized messages stay out of the context
+        policy.apply(&mut messages, 1000, 100);
+        assert_eq!(messages[1].context, ContextMark::Summarized);
+    }
+
+    #[test]
+    fn disabled_policy_restores_the_history() {
+        let mut messages = history(3);
+        policy(ContextStrategy::KeepLastTurns).apply(&mut messages, 1000, 900);
+        let disabled = ContextPolicy::default();
+        disabled.apply(&mut messages, 1000, 900);
+        assert!(marks(&messages).iter().all(|&m| m == ContextMark::Included));
+    }
+
+    #[test]
+    fn estimate_includes_the_new_prompt() {
+        let mut messages = history(1);
+        let before = estimate_tokens(&messages);
+        assert_eq!(before, 2 + 258 + 2);
+
+        messages.push(Message::user(
+            "x".repeat(400),
+            GeminiModel::default(),
+            vec![],
+        ));
+        assert_eq!(estimate_tokens(&messages), before + 100);
+
+        messages[0].context = ContextMark::FilesExcluded;
+        assert_eq!(estimate_tokens(&messages), before - 258 + 100);
+    }
+}
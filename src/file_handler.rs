@@ -62,6 +62,9 @@ pub struct Attachment {
     pub mime: String,
     #[serde(skip)]
     pub state: AttachmentState,
+    /// Sent once through the context cache instead of with every request.
+    #[serde(default)]
+    pub pinned: bool,
 }
 
 impl Attachment {
@@ -73,10 +76,66 @@ impl Attachment {
             path,
             mime,
             state: AttachmentState::Local,
+            pinned: false,
         }
     }
 }
 
+/// Action requested on a file shown in a message
+pub enum FileAction {
+    /// Attach a copy of the file to the chatbox
+    UseAsInput(Attachment),
+}
+
+/// Decodes inline data returned by the model and stores it in the app storage dir.
+pub async fn save_inline_data(blob: &Blob) -> Result<Attachment> {
+    let bytes = base64::engine::general_purpose::STANDARD.decode(&blob.data)?;
+
+    let dir = eframe::storage_dir(crate::TITLE)
+        .ok_or_else(|| anyhow!("Could not determine the storage directory"))?
+        .join("generated");
+    tokio::fs::create_dir_all(&dir).await?;
+
+    let extension = mime_guess::get_mime_extensions_str(&blob.mime_type)
+        .and_then(|exts| exts.first())
+        .copied()
+        .unwrap_or("bin");
+    let name = format!(
+        "{}.{extension}",
+        chrono::Local::now().format("%Y%m%d-%H%M%S-%3f")
+    );
+    let path = dir.join(name);
+    tokio::fs::write(&path, &bytes).await?;
+    log::info!(
+        "Saved {} bytes of generated {} to {}",
+        bytes.len(),
+        blob.mime_type,
+        path.display()
+    );
+
+    Ok(Attachment {
+        path,
+        mime: blob.mime_type.clone(),
+        state: AttachmentState::Local,
+        pinned: false,
+    })
+}
+
+async fn save_file_as(path: PathBuf) {
+    let mut dialog = rfd::AsyncFileDialog::new();
+    if let Some(name) = path.file_name() {
+        dialog = dialog.set_file_name(name.to_string_lossy());
+    }
+    let Some(file) = dialog.save_file().await else {
+        log::info!("save cancelled");
+        return;
+    };
+
+    if let Err(e) = tokio::fs::copy(&path, file.path()).await {
+        log::error!("failed to save file to {}: {e}", file.path().display());
+    }
+}
+
 /// Returns either a Part with inline data or a FileHandle of the uploaded file
 pub enum FileResult {
     /// Inline data part for direct use
@@ -253,10 +312,15 @@ pub async fn convert_file_to_part(
     }
 }
 
-pub fn show_files(ui: &mut egui::Ui, files: &mut Vec<Attachment>, mutate: bool) {
+pub fn show_files(
+    ui: &mut egui::Ui,
+    files: &mut Vec<Attachment>,
+    mutate: bool,
+) -> Option<FileAction> {
     const MAX_PREVIEW_HEIGHT: f32 = 128.0;
     let pointer_pos = ui.input(|i| i.pointer.interact_pos());
     let mut showing_x = false;
+    let mut action = None;
 
     files.retain_mut(|file| {
         let file_path = &mut file.path;
@@ -322,6 +386,9 @@ pub fn show_files(ui: &mut egui::Ui, files: &mut Vec<Attachment>, mutate: bool)
                     }
 
                     let mut text = file_path.file_name().unwrap_or_default().to_string_lossy();
+                    if file.pinned {
+                        text.to_mut().insert_str(0, "📌 ");
+                    }
                     if !is_exist {
                         text.to_mut().push_str(" (FILE NOT FOUND)");
                     }
@@ -342,7 +409,11 @@ pub fn show_files(ui: &mut egui::Ui, files: &mut Vec<Attachment>, mutate: bool)
 
         let interact_resp = ui
             .interact(resp.rect, resp.id.with("interact"), egui::Sense::click())
-            .on_hover_text(&path_string);
+            .on_hover_text(if mutate {
+                path_string.clone()
+            } else {
+                format!("{path_string}\nRight-click for more actions")
+            });
         if interact_resp.hovered() {
             ui.ctx().set_cursor_icon(egui::CursorIcon::PointingHand);
         }
@@ -354,6 +425,37 @@ pub fn show_files(ui: &mut egui::Ui, files: &mut Vec<Attachment>, mutate: bool)
             }
         }
 
+        if !mutate && is_exist {
+            interact_resp.context_menu(|ui| {
+                if ui.button("💾 Save as…").clicked() {
+                    let path = file_path.clone();
+                    tokio::spawn(async move {
+                        save_file_as(path).await;
+                    });
+                    ui.close();
+                }
+                if ui.button("📥 Use as input").clicked() {
+                    action = Some(FileAction::UseAsInput(Attachment::from_path(
+                        file_path.clone(),
+                    )));
+                    ui.close();
+                }
+                let pin_label = if file.pinned {
+                    "📌 Unpin from context cache"
+                } else {
+                    "📌 Pin to context cache"
+                };
+                if ui
+                    .button(pin_label)
+                    .on_hover_text("Pinned files are cached once when context caching is enabled")
+                    .clicked()
+                {
+                    file.pinned = !file.pinned;
+                    ui.close();
+                }
+            });
+        }
+
         if !mutate || showing_x {
             return true;
         }
@@ -395,4 +497,6 @@ pub fn show_files(ui: &mut egui::Ui, files: &mut Vec<Attachment>, mutate: bool)
 
         true
     });
+
+    action
 }
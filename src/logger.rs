@@ -1,5 +1,5 @@
 use log::{Level, Metadata, Record, SetLoggerError};
-use std::sync::{mpsc, Mutex, OnceLock};
+use std::sync::{mpsc, Mutex, OnceLock, PoisonError, RwLock};
 
 pub struct LogEvent {
     pub level: Level,
@@ -8,6 +8,49 @@ pub struct LogEvent {
 
 static LOG_RECEIVER: OnceLock<Mutex<mpsc::Receiver<LogEvent>>> = OnceLock::new();
 
+/// Values masked in every log message, e.g. API keys.
+static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+
+/// Sets the values that must never show up in the logs.
+pub fn set_secrets<'a>(secrets: impl IntoIterator<Item = &'a str>) {
+    let secrets: Vec<String> = secrets
+        .into_iter()
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect();
+    if *SECRETS.read().unwrap_or_else(PoisonError::into_inner) != secrets {
+        *SECRETS.write().unwrap_or_else(PoisonError::into_inner) = secrets;
+    }
+}
+
+/// Masks the known secrets, and API keys passed as the `key` query parameter of a URL
+/// (e.g. in a request error).
+fn redact(mut message: String) -> String {
+    for secret in SECRETS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+    {
+        if message.contains(secret.as_str()) {
+            message = message.replace(secret.as_str(), "***");
+        }
+    }
+    for param in ["?key=", "&key="] {
+        let mut offset = 0;
+        while let Some(start) = message[offset..]
+            .find(param)
+            .map(|i| offset + i + param.len())
+        {
+            let end = message[start..]
+                .find(|c: char| matches!(c, '&' | '#' | ')' | '"' | '\'') || c.is_whitespace())
+                .map_or(message.len(), |i| start + i);
+            message.replace_range(start..end, "***");
+            offset = start + 3;
+        }
+    }
+    message
+}
+
 struct GlobalLogger {
     inner: env_logger::Logger,
     sender: Mutex<mpsc::Sender<LogEvent>>,
@@ -19,16 +62,29 @@ impl log::Log for GlobalLogger {
     }
 
     fn log(&self, record: &Record) {
+        let forward = self.inner.enabled(record.metadata());
+        let capture = record.level() <= Level::Warn;
+        if !forward && !capture {
+            return;
+        }
+        // We format the message immediately, so secrets can be masked.
+        let msg = redact(record.args().to_string());
+
         // Always forward to env_logger for console/terminal output
-        if self.inner.enabled(record.metadata()) {
-            self.inner.log(record);
+        if forward {
+            self.inner.log(
+                &Record::builder()
+                    .args(format_args!("{msg}"))
+                    .metadata(record.metadata().clone())
+                    .module_path(record.module_path())
+                    .file(record.file())
+                    .line(record.line())
+                    .build(),
+            );
         }
 
         // Capture Warn and Error logs for the UI
-        if record.level() <= Level::Warn {
-            // We format the message immediately.
-            let msg = format!("{}", record.args());
-
+        if capture {
             // Send to the channel
             if let Ok(sender) = self.sender.lock() {
                 let _ = sender.send(LogEvent {
@@ -3,13 +3,25 @@
 
 use eframe::egui;
 use sessions::Sessions;
+mod api_error;
+mod cache;
 mod chat;
 mod chat_completion;
+mod compare;
+mod config;
+mod context;
 mod easymark;
 mod file_handler;
 mod logger;
+mod models;
+mod presets;
+mod prompts;
+mod safety;
+mod secrets;
 mod sessions;
 mod style;
+mod structured;
+mod tools;
 mod widgets;
 
 const TITLE: &str = "GeminiD";
@@ -75,14 +87,16 @@ impl Geminid {
         );
 
         if let Some(storage) = cc.storage {
-            if let Some(app_state) = eframe::get_value::<Self>(storage, eframe::APP_KEY) {
+            if let Some(mut app_state) = eframe::get_value::<Self>(storage, eframe::APP_KEY) {
                 log::info!("app state successfully restored from storage");
+                app_state.restore_settings();
                 return app_state;
             }
         }
 
 
         let mut app = Self::default();
+        app.restore_settings();
         if app.sessions.try_restore_autosave() {
             log::error!("app state is not saved in storage. This is a bug!");
             log::info!("Disaster recovery successful.");
@@ -90,6 +104,16 @@ impl Geminid {
 
         app
     }
+
+    /// Reads the keys and tokens kept apart from the app state, then applies the
+    /// environment and `config.toml`. Keys found in the state are kept, they come from
+    /// a version that stored them there.
+    fn restore_settings(&mut self) {
+        let settings = &mut self.sessions.settings;
+        settings.restore_secrets(secrets::Secrets::load());
+        config::Overrides::load().apply(settings);
+        settings.hide_secrets_in_logs();
+    }
 }
 
 impl eframe::App for Geminid { // todo rename
@@ -100,6 +124,7 @@ impl eframe::App for Geminid { // todo rename
     fn save(&mut self, storage: &mut dyn eframe::Storage) {
         log::debug!("saving app state");
         eframe::set_value(storage, eframe::APP_KEY, self);
+        self.sessions.settings.secrets().save();
         self.sessions.save_autosave();
     }
 }
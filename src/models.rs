@@ -0,0 +1,654 @@
+use crate::widgets::{ApiKeyProfile, GeminiModel};
+use anyhow::{anyhow, Result};
+use eframe::egui;
+use gemini_rust::GenerationConfig;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    path::PathBuf,
+    sync::{Arc, LazyLock, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
+};
+
+/// Endpoint requests go to unless a base URL is set.
+pub const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com/v1beta/";
+
+/// The fetched list is refreshed in the background once it gets older than this.
+const MAX_AGE: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Models offered until the list is fetched from the API for the first time.
+const BUILTIN_MODELS: &[&str] = &[
+    "gemini-3-flash-preview",
+    "gemini-3-pro-preview",
+    "gemini-2.5-pro",
+    "gemini-2.5-flash",
+    "gemini-2.5-flash-lite",
+    "gemini-2.0-flash",
+    "gemini-2.0-flash-lite",
+    "gemma-3-27b-it",
+    "gemma-3-12b-it",
+    "gemma-3-4b-it",
+    "gemma-3-1b-it",
+    "gemma-3n-e4b-it",
+    "gemma-3n-e2b-it",
+];
+
+/// Fetched and user-added models, shared by every model picker.
+static CATALOGUE: LazyLock<RwLock<ModelCatalogue>> =
+    LazyLock::new(|| RwLock::new(ModelCatalogue::load()));
+
+pub fn catalogue() -> RwLockReadGuard<'static, ModelCatalogue> {
+    CATALOGUE.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+pub fn catalogue_mut() -> RwLockWriteGuard<'static, ModelCatalogue> {
+    CATALOGUE.write().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// What the API tells about a model.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelInfo {
+    /// Model ID without the `models/` prefix, e.g. `gemini-2.5-pro`.
+    pub id: String,
+    pub display_name: String,
+    pub description: String,
+    pub input_token_limit: Option<u32>,
+    pub output_token_limit: Option<u32>,
+    /// Whether the model thinks, if the API says so.
+    pub thinking: Option<bool>,
+    /// No longer listed by the API, or announced as deprecated.
+    pub deprecated: bool,
+    /// Added by the user rather than fetched.
+    pub custom: bool,
+}
+
+impl ModelInfo {
+    fn from_id(id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn from_json(model: &Value) -> Option<Self> {
+        let id = model["name"].as_str()?.trim_start_matches("models/");
+        let generates = model["supportedGenerationMethods"]
+            .as_array()
+            .is_some_and(|methods| methods.iter().any(|m| m == "generateContent"));
+        if !generates || !is_valid_id(id) {
+            return None;
+        }
+
+        let description = model["description"].as_str().unwrap_or_default();
+        let lowercase = description.to_lowercase();
+        let limit = |key: &str| model[key].as_u64().and_then(|n| u32::try_from(n).ok());
+        Some(Self {
+            id: id.to_string(),
+            display_name: model["displayName"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
+            description: description.to_string(),
+            input_token_limit: limit("inputTokenLimit"),
+            output_token_limit: limit("outputTokenLimit"),
+            thinking: model["thinking"].as_bool(),
+            deprecated: lowercase.contains("deprecat") || lowercase.contains("discontinued"),
+            custom: false,
+        })
+    }
+
+    #[inline]
+    pub fn model(&self) -> GeminiModel {
+        GeminiModel::new(&self.id)
+    }
+
+    /// Family the model is grouped under, e.g. `Gemini 2.5` or `Gemma 3n`.
+    pub fn family(&self) -> String {
+        if self.custom {
+            return "Custom".to_string();
+        }
+        let mut parts = self.id.split('-');
+        let name = parts.next().unwrap_or_default();
+        let mut family: String = name
+            .chars()
+            .take(1)
+            .flat_map(char::to_uppercase)
+            .chain(name.chars().skip(1))
+            .collect();
+        if let Some(version) = parts
+            .next()
+            .filter(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+        {
+            family.push(' ');
+            family.push_str(version);
+        }
+        family
+    }
+
+    pub fn label(&self) -> String {
+        if self.deprecated {
+            format!("{} (deprecated)", self.id)
+        } else {
+            self.id.clone()
+        }
+    }
+
+    pub fn hover_text(&self) -> String {
+        let mut text = if self.display_name.is_empty() {
+            self.id.clone()
+        } else {
+            self.display_name.clone()
+        };
+        if !self.description.is_empty() {
+            text.push_str("\n\n");
+            text.push_str(&self.description);
+        }
+        if let (Some(input), Some(output)) = (self.input_token_limit, self.output_token_limit) {
+            text.push_str(&format!(
+                "\n\nUp to {input} input and {output} output tokens"
+            ));
+        }
+        if self.deprecated {
+            text.push_str("\n\n⚠ This model is deprecated and may stop working");
+        }
+        text
+    }
+}
+
+/// Whether `id` looks like a model ID. Model IDs are stored as identifiers in the app
+/// state, so only a few characters are allowed.
+pub fn is_valid_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | '+'))
+}
+
+/// Models available to the pickers, cached in `models.json` in the storage directory.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ModelCatalogue {
+    /// Models returned by the API, empty until the list is fetched.
+    fetched: Vec<ModelInfo>,
+    pub fetched_at: Option<chrono::DateTime<chrono::Utc>>,
+    /// Model IDs added by the user.
+    pub custom: Vec<String>,
+    /// Every model grouped by family, rebuilt when the fetched or custom models change.
+    #[serde(skip)]
+    families: Arc<Vec<(String, Vec<ModelInfo>)>>,
+}
+
+impl ModelCatalogue {
+    fn path() -> Option<PathBuf> {
+        eframe::storage_dir(crate::TITLE).map(|p| p.join("models.json"))
+    }
+
+    fn load() -> Self {
+        let mut catalogue = match Self::path().filter(|p| p.exists()) {
+            Some(path) => std::fs::read(&path)
+                .map_err(anyhow::Error::from)
+                .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?))
+                .unwrap_or_else(|e| {
+                    log::error!("failed to read the model list cache: {e}");
+                    Self::default()
+                }),
+            None => Self::default(),
+        };
+        catalogue.rebuild();
+        catalogue
+    }
+
+    fn save(&self) {
+        let Some(path) = Self::path() else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| std::fs::write(&path, serde_json::to_vec(self).unwrap_or_default()));
+        if let Err(e) = result {
+            log::error!("failed to save the model list cache: {e}");
+        }
+    }
+
+    /// Whether the list should be fetched again.
+    pub fn is_stale(&self) -> bool {
+        self.fetched_at.is_none_or(|at| {
+            chrono::Utc::now() - at > chrono::Duration::from_std(MAX_AGE).unwrap_or_default()
+        })
+    }
+
+    /// Replaces the fetched models and saves the catalogue.
+    pub fn set_fetched(&mut self, models: Vec<ModelInfo>) {
+        self.fetched = models;
+        self.fetched_at = Some(chrono::Utc::now());
+        self.rebuild();
+        self.save();
+    }
+
+    /// Adds a model ID that is not listed by the API, e.g. a new preview.
+    pub fn add_custom(&mut self, id: &str) -> Result<()> {
+        let id = id.trim().trim_start_matches("models/");
+        if !is_valid_id(id) {
+            return Err(anyhow!("`{id}` is not a valid model ID"));
+        }
+        if !self.custom.iter().any(|c| c == id) {
+            self.custom.push(id.to_string());
+            self.rebuild();
+            self.save();
+        }
+        Ok(())
+    }
+
+    pub fn remove_custom(&mut self, id: &str) {
+        self.custom.retain(|c| c != id);
+        self.rebuild();
+        self.save();
+    }
+
+    /// Merges the fetched, built-in and custom models, and groups them by family.
+    /// Families keep the order they first appear in, deprecated models come last in theirs.
+    fn rebuild(&mut self) {
+        let mut families: Vec<(String, Vec<ModelInfo>)> = Vec::new();
+        for model in self.merged() {
+            let family = model.family();
+            match families.iter_mut().find(|(name, _)| *name == family) {
+                Some((_, models)) => models.push(model),
+                None => families.push((family, vec![model])),
+            }
+        }
+        for (_, models) in &mut families {
+            models.sort_by_key(|m| m.deprecated);
+        }
+        self.families = Arc::new(families);
+    }
+
+    /// Every model, with the built-in ones the API no longer lists marked as deprecated.
+    fn merged(&self) -> Vec<ModelInfo> {
+        let mut models = if self.fetched.is_empty() {
+            BUILTIN_MODELS
+                .iter()
+                .map(|id| ModelInfo::from_id(id))
+                .collect()
+        } else {
+            let mut models = self.fetched.clone();
+            for id in BUILTIN_MODELS {
+                if !models.iter().any(|m| m.id == *id) {
+                    models.push(ModelInfo {
+                        deprecated: true,
+                        ..ModelInfo::from_id(id)
+                    });
+                }
+            }
+            models
+        };
+        for id in &self.custom {
+            if !models.iter().any(|m| m.id == *id) {
+                models.push(ModelInfo {
+                    custom: true,
+                    ..ModelInfo::from_id(id)
+                });
+            }
+        }
+        models
+    }
+
+    /// Every model, family by family.
+    pub fn models(&self) -> impl Iterator<Item = &ModelInfo> {
+        self.families.iter().flat_map(|(_, models)| models)
+    }
+
+    pub fn get(&self, model: GeminiModel) -> Option<&ModelInfo> {
+        self.models().find(|m| m.id == model.id())
+    }
+
+    /// What the model can do, using the fetched limits when available.
+    pub fn capabilities(&self, model: GeminiModel) -> Capabilities {
+        let mut capabilities = Capabilities::for_id(model.id());
+        let Some(info) = self.fetched.iter().find(|m| m.id == model.id()) else {
+            return capabilities;
+        };
+        if let Some(limit) = info.input_token_limit {
+            capabilities.input_token_limit = limit;
+        }
+        if let Some(limit) = info.output_token_limit {
+            capabilities.output_token_limit = limit;
+        }
+        match info.thinking {
+            Some(false) => {
+                capabilities.thinking = false;
+                capabilities.thinking_budget = None;
+            }
+            Some(true) if !capabilities.thinking => {
+                capabilities.thinking = true;
+                capabilities.thinking_budget = Some(ThinkingBudget::PRO);
+            }
+            _ => {}
+        }
+        capabilities
+    }
+
+    /// Models grouped by family. Shared, so that the lock isn't held while they are shown.
+    pub fn families(&self) -> Arc<Vec<(String, Vec<ModelInfo>)>> {
+        Arc::clone(&self.families)
+    }
+}
+
+/// Attachments accepted by Gemini models.
+const GEMINI_INPUT: &[&str] = &["text/*", "image/*", "video/*", "audio/*", "application/pdf"];
+const GEMMA_INPUT: &[&str] = &["text/*", "image/*"];
+const TEXT_INPUT: &[&str] = &["text/*"];
+
+/// Values the thinking budget can take, besides -1 for a dynamic budget.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThinkingBudget {
+    pub min: i32,
+    pub max: i32,
+    /// Whether a budget of 0 turns thinking off.
+    pub can_disable: bool,
+}
+
+impl ThinkingBudget {
+    const PRO: Self = Self {
+        min: 128,
+        max: 32768,
+        can_disable: false,
+    };
+    const FLASH: Self = Self {
+        min: 1,
+        max: 24576,
+        can_disable: true,
+    };
+    const FLASH_LITE: Self = Self {
+        min: 512,
+        max: 24576,
+        can_disable: true,
+    };
+
+    pub fn clamp(&self, budget: i32) -> i32 {
+        match budget {
+            -1 => -1,
+            0 if self.can_disable => 0,
+            budget => budget.clamp(self.min, self.max),
+        }
+    }
+}
+
+/// What a model can do. Known from the model ID, refined by the fetched model list.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub input_token_limit: u32,
+    pub output_token_limit: u32,
+    pub thinking: bool,
+    /// `None` if the model thinks, but the budget can't be set.
+    pub thinking_budget: Option<ThinkingBudget>,
+    /// MIME types of the attachments the model can read, `image/*` style.
+    pub input_mime_types: &'static [&'static str],
+    /// Function calling, code execution and search grounding.
+    pub tools: bool,
+    pub image_output: bool,
+    /// Smallest prefix the model can cache in tokens, `None` without explicit caching.
+    pub min_cache_tokens: Option<u32>,
+}
+
+impl Capabilities {
+    fn for_id(id: &str) -> Self {
+        let gemma = id.starts_with("gemma");
+        let image_output = id.contains("image");
+        let recent = id.starts_with("gemini-2.5") || id.starts_with("gemini-3");
+        let thinking_budget = if gemma || image_output {
+            None
+        } else if id.starts_with("gemini-2.5-flash-lite") {
+            Some(ThinkingBudget::FLASH_LITE)
+        } else if id.starts_with("gemini-2.5-flash") || id.starts_with("gemini-3-flash") {
+            Some(ThinkingBudget::FLASH)
+        } else if recent {
+            Some(ThinkingBudget::PRO)
+        } else {
+            None
+        };
+
+        Self {
+            input_token_limit: if gemma {
+                32_768
+            } else if id.starts_with("gemini-1.5-pro") {
+                2_097_152
+            } else {
+                1_048_576
+            },
+            output_token_limit: if recent && !image_output {
+                65_536
+            } else {
+                8_192
+            },
+            thinking: thinking_budget.is_some() || id.contains("thinking"),
+            thinking_budget,
+            input_mime_types: if id.starts_with("gemma-3-1b") || id.starts_with("gemma-3n") {
+                TEXT_INPUT
+            } else if gemma {
+                GEMMA_INPUT
+            } else {
+                GEMINI_INPUT
+            },
+            tools: !gemma && !image_output,
+            image_output,
+            min_cache_tokens: if gemma || image_output || id.contains("-exp") {
+                None
+            } else if recent && !id.contains("pro") {
+                Some(1024)
+            } else {
+                Some(4096)
+            },
+        }
+    }
+
+    /// Whether an attachment with this MIME type can be sent to the model.
+    pub fn accepts(&self, mime: &str) -> bool {
+        // Documents other than PDF are sent as plain text, see `file_handler::convert_file_to_part`
+        let mime = if mime.starts_with("application/") && mime != "application/pdf" {
+            "text/plain"
+        } else {
+            mime
+        };
+        self.input_mime_types
+            .iter()
+            .any(|pattern| match pattern.strip_suffix("/*") {
+                Some(kind) => mime.split('/').next() == Some(kind),
+                None => *pattern == mime,
+            })
+    }
+
+    /// Clamps or removes the settings the model doesn't support.
+    pub fn restrict(&self, config: &mut GenerationConfig) {
+        if let Some(max) = &mut config.max_output_tokens {
+            *max = (*max).min(i32::try_from(self.output_token_limit).unwrap_or(i32::MAX));
+        }
+        if !self.thinking {
+            config.thinking_config = None;
+        } else if let Some(thinking) = &mut config.thinking_config {
+            thinking.thinking_budget = self
+                .thinking_budget
+                .zip(thinking.thinking_budget)
+                .map(|(range, budget)| range.clamp(budget));
+        }
+    }
+
+    /// One line overview, e.g. `1048576 input · 65536 output tokens · thinking · tools`.
+    pub fn summary(&self) -> String {
+        let mut summary = format!(
+            "{} input · {} output tokens",
+            self.input_token_limit, self.output_token_limit
+        );
+        for (supported, name) in [
+            (self.thinking, "thinking"),
+            (self.tools, "tools"),
+            (self.image_output, "image output"),
+        ] {
+            if supported {
+                summary.push_str(" · ");
+                summary.push_str(name);
+            }
+        }
+        summary
+    }
+}
+
+/// Lists the catalogue grouped by family, adding a widget for each model with `add_model`.
+pub fn show_grouped(
+    ui: &mut egui::Ui,
+    mut add_model: impl FnMut(&mut egui::Ui, &ModelInfo) -> egui::Response,
+) {
+    let families = catalogue().families();
+    for (i, (family, models)) in families.iter().enumerate() {
+        if i > 0 {
+            ui.separator();
+        }
+        ui.weak(family);
+        for model in models {
+            ui.scope(|ui| {
+                if model.deprecated {
+                    ui.visuals_mut().override_text_color = Some(ui.visuals().weak_text_color());
+                }
+                add_model(ui, model).on_hover_text(model.hover_text());
+            });
+        }
+    }
+}
+
+/// Settings section listing the catalogue state and the custom models.
+/// Returns `true` if the user asked to fetch the list again.
+pub fn show_settings(ui: &mut egui::Ui) -> bool {
+    let mut refresh = false;
+    let (fetched_at, custom) = {
+        let catalogue = catalogue();
+        (catalogue.fetched_at, catalogue.custom.clone())
+    };
+
+    ui.horizontal(|ui| {
+        match fetched_at {
+            Some(at) => ui.label(format!(
+                "Model list fetched on {}",
+                at.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M")
+            )),
+            None => ui.label("Using the built-in model list"),
+        };
+        refresh = ui
+            .button("🔄 Refresh")
+            .on_hover_text("Fetch the models available to your API key")
+            .clicked();
+    });
+
+    for id in custom {
+        ui.horizontal(|ui| {
+            ui.label(&id);
+            if ui
+                .button("❌")
+                .on_hover_text("Remove custom model")
+                .clicked()
+            {
+                catalogue_mut().remove_custom(&id);
+            }
+        });
+    }
+
+    let id = ui.id().with("new_model_id");
+    let mut new_id: String = ui.data_mut(|d| d.get_temp(id)).unwrap_or_default();
+    ui.horizontal(|ui| {
+        ui.add(egui::TextEdit::singleline(&mut new_id).hint_text("gemini-2.5-pro-exp"));
+        if ui
+            .add_enabled(!new_id.trim().is_empty(), egui::Button::new("➕ Add model"))
+            .clicked()
+        {
+            match catalogue_mut().add_custom(&new_id) {
+                Ok(()) => new_id.clear(),
+                Err(e) => log::error!("{e}"),
+            }
+        }
+    });
+    ui.data_mut(|d| d.insert_temp(id, new_id));
+
+    refresh
+}
+
+/// Fetches the models that can generate content from the models endpoint.
+pub async fn fetch(key_profile: ApiKeyProfile) -> Result<Vec<ModelInfo>> {
+    let client = crate::widgets::http_client(key_profile.proxy_path).build()?;
+    let base_url = key_profile
+        .base_url
+        .as_deref()
+        .filter(|url| !url.is_empty())
+        .unwrap_or(DEFAULT_BASE_URL);
+    let url = format!("{}/models", base_url.trim_end_matches('/'));
+    let mut models = Vec::new();
+    let mut page_token: Option<String> = None;
+    loop {
+        let mut request = client
+            .get(&url)
+            .header("x-goog-api-key", &key_profile.key)
+            .query(&[("pageSize", "1000")]);
+        if let Some(token) = &page_token {
+            request = request.query(&[("pageToken", token)]);
+        }
+        let response = request.send().await?;
+        let status = response.status();
+        let body: Value = serde_json::from_slice(&response.bytes().await?)?;
+        if !status.is_success() {
+            let message = body["error"]["message"].as_str().unwrap_or_default();
+            return Err(anyhow!("failed to list models ({status}): {message}"));
+        }
+
+        models.extend(
+            body["models"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(ModelInfo::from_json),
+        );
+        match body["nextPageToken"].as_str() {
+            Some(token) if !token.is_empty() => page_token = Some(token.to_string()),
+            _ => break,
+        }
+    }
+    Ok(models)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thinking_budget_is_clamped_to_the_model_range() {
+        let pro = ThinkingBudget::PRO;
+        assert_eq!(pro.clamp(-1), -1);
+        assert_eq!(pro.clamp(0), 128);
+        assert_eq!(pro.clamp(50), 128);
+        assert_eq!(pro.clamp(1000), 1000);
+        assert_eq!(pro.clamp(100_000), 32768);
+
+        let flash = ThinkingBudget::FLASH;
+        assert_eq!(flash.clamp(0), 0);
+        assert_eq!(flash.clamp(30_000), 24576);
+        assert_eq!(ThinkingBudget::FLASH_LITE.clamp(100), 512);
+    }
+
+    #[test]
+    fn accepts_matches_wildcards_and_exact_types() {
+        let gemini = Capabilities::for_id("gemini-2.5-flash");
+        assert!(gemini.accepts("image/png"));
+        assert!(gemini.accepts("audio/mpeg"));
+        assert!(gemini.accepts("application/pdf"));
+        // Other documents are sent as plain text
+        assert!(gemini.accepts("application/json"));
+        assert!(!gemini.accepts("model/gltf+json"));
+
+        let gemma = Capabilities::for_id("gemma-3-27b-it");
+        assert!(gemma.accepts("image/jpeg"));
+        assert!(!gemma.accepts("video/mp4"));
+        assert!(!gemma.accepts("application/pdf"));
+
+        let text_only = Capabilities::for_id("gemma-3-1b-it");
+        assert!(text_only.accepts("text/markdown"));
+        assert!(!text_only.accepts("image/png"));
+    }
+}
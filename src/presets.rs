@@ -0,0 +1,188 @@
+use crate::widgets::{name_edit, ModelPicker};
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+
+/// A saved model, inference settings and system prompt, e.g. a persona.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub name: String,
+    pub model_picker: ModelPicker,
+}
+
+impl Preset {
+    /// Drops the directories the file tools may access. They are local to the machine
+    /// the preset was made on, and must be granted again by whoever uses it.
+    pub fn clear_local_paths(&mut self) {
+        self.model_picker.clear_tool_roots();
+    }
+
+    /// Asks where to save the preset and writes it as JSON, without the local paths.
+    pub async fn export(mut self) {
+        self.clear_local_paths();
+        let Some(file) = rfd::AsyncFileDialog::new()
+            .add_filter("JSON file", &["json"])
+            .set_file_name(format!("{}.json", self.name))
+            .save_file()
+            .await
+        else {
+            log::warn!("no file selected");
+            return;
+        };
+
+        let Ok(f) = std::fs::File::create(file.path())
+            .map_err(|e| log::error!("failed to create file: {e}"))
+        else {
+            return;
+        };
+
+        let _ = serde_json::to_writer_pretty(f, &self)
+            .map_err(|e| log::error!("failed to save preset: {e}"));
+    }
+}
+
+/// The user's presets, stored in the settings.
+#[derive(Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Presets {
+    pub presets: Vec<Preset>,
+    /// Name of the preset new chats start from, instead of the default model.
+    pub default: Option<String>,
+}
+
+impl Presets {
+    pub fn get(&self, name: &str) -> Option<&Preset> {
+        self.presets.iter().find(|p| p.name == name)
+    }
+
+    /// Model picker new chats start from, if a default preset is set.
+    pub fn default_picker(&self) -> Option<&ModelPicker> {
+        self.default
+            .as_deref()
+            .and_then(|name| self.get(name))
+            .map(|p| &p.model_picker)
+    }
+
+    /// Saves `model_picker` under `name`, replacing the preset with the same name.
+    pub fn save(&mut self, name: &str, model_picker: &ModelPicker) {
+        let preset = Preset {
+            name: name.to_string(),
+            model_picker: model_picker.clone(),
+        };
+        match self.presets.iter_mut().find(|p| p.name == name) {
+            Some(existing) => *existing = preset,
+            None => self.presets.push(preset),
+        }
+    }
+
+    /// Adds an imported preset, renaming it if the name is taken.
+    pub fn add(&mut self, mut preset: Preset) -> &Preset {
+        let base = if preset.name.trim().is_empty() {
+            "Imported".to_string()
+        } else {
+            preset.name.trim().to_string()
+        };
+        preset.name = base.clone();
+        let mut n = 2;
+        while self.get(&preset.name).is_some() {
+            preset.name = format!("{base} ({n})");
+            n += 1;
+        }
+        self.presets.push(preset);
+        self.presets.last().unwrap()
+    }
+
+    /// Lets the user apply a preset to `model_picker`, or save it as a new one.
+    pub fn show_bar(&mut self, ui: &mut egui::Ui, model_picker: &mut ModelPicker) {
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(!self.presets.is_empty(), |ui| {
+                egui::ComboBox::from_id_salt("apply_preset")
+                    .selected_text("Apply preset")
+                    .show_ui(ui, |ui| {
+                        for preset in &self.presets {
+                            if ui
+                                .selectable_label(false, &preset.name)
+                                .on_hover_text(preset.model_picker.selected.to_string())
+                                .clicked()
+                            {
+                                *model_picker = preset.model_picker.clone();
+                            }
+                        }
+                    });
+            });
+
+            let id = ui.id().with("new_preset_name");
+            let mut name: String = ui.data_mut(|d| d.get_temp(id)).unwrap_or_default();
+            ui.add(
+                egui::TextEdit::singleline(&mut name)
+                    .hint_text("Preset name")
+                    .desired_width(120.0),
+            );
+            let overwrites = self.get(name.trim()).is_some();
+            if ui
+                .add_enabled(!name.trim().is_empty(), egui::Button::new("💾 Save"))
+                .on_hover_text(if overwrites {
+                    "Replace the preset with these settings"
+                } else {
+                    "Save the model, settings and system prompt as a preset"
+                })
+                .clicked()
+            {
+                self.save(name.trim(), model_picker);
+                name.clear();
+            }
+            ui.data_mut(|d| d.insert_temp(id, name));
+        });
+    }
+
+    /// Settings section listing the presets.
+    /// Returns `true` if the user asked to import a preset.
+    pub fn show(&mut self, ui: &mut egui::Ui) -> bool {
+        egui::ComboBox::from_label("New chats start from")
+            .selected_text(self.default.as_deref().unwrap_or("Default model"))
+            .show_ui(ui, |ui| {
+                ui.selectable_value(&mut self.default, None, "Default model");
+                for preset in &self.presets {
+                    ui.selectable_value(&mut self.default, Some(preset.name.clone()), &preset.name);
+                }
+            });
+
+        let mut remove = None;
+        let names: Vec<String> = self.presets.iter().map(|p| p.name.clone()).collect();
+        for (i, preset) in self.presets.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                ui.horizontal(|ui| {
+                    let old_name = preset.name.clone();
+                    // Presets are looked up by name, a duplicate would hide this one
+                    let is_taken =
+                        |name: &str| names.iter().enumerate().any(|(j, n)| j != i && n == name);
+                    if name_edit(ui, &mut preset.name, 160.0, is_taken)
+                        && self.default.as_ref() == Some(&old_name)
+                    {
+                        self.default = Some(preset.name.clone());
+                    }
+                    ui.weak(preset.model_picker.selected.to_string());
+                    if ui.button("📤").on_hover_text("Export as JSON").clicked() {
+                        let preset = preset.clone();
+                        tokio::spawn(async move {
+                            preset.export().await;
+                        });
+                    }
+                    if ui.button("🗑").on_hover_text("Remove preset").clicked() {
+                        remove = Some(i);
+                    }
+                });
+            });
+        }
+        if let Some(i) = remove {
+            let preset = self.presets.remove(i);
+            if self.default.as_ref() == Some(&preset.name) {
+                self.default = None;
+            }
+        }
+
+        ui.button("📥 Import…")
+            .on_hover_text("Add a preset shared as a JSON file")
+            .clicked()
+    }
+}
@@ -0,0 +1,316 @@
+use crate::widgets::GeminiModel;
+use eframe::egui;
+use serde::{Deserialize, Serialize};
+use std::ops::Range;
+
+/// A reusable prompt with `{{variable}}` placeholders, inserted by typing `/name`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptTemplate {
+    pub name: String,
+    pub text: String,
+    /// System prompt set on the chat when the template is used.
+    pub system_prompt: Option<String>,
+    /// Model selected when the template is used.
+    pub model: Option<GeminiModel>,
+}
+
+impl PromptTemplate {
+    fn new(name: &str, text: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            text: text.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// Names of the placeholders, in order of first appearance.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables: Vec<String> = Vec::new();
+        for (_, name) in placeholders(&self.text) {
+            if !name.is_empty() && !variables.iter().any(|v| v == name) {
+                variables.push(name.to_string());
+            }
+        }
+        variables
+    }
+
+    /// Replaces the placeholders with the given values.
+    pub fn render(&self, values: &[(String, String)]) -> String {
+        let mut text = String::with_capacity(self.text.len());
+        let mut end = 0;
+        for (range, name) in placeholders(&self.text) {
+            if let Some((_, value)) = values.iter().find(|(n, _)| n == name) {
+                text.push_str(&self.text[end..range.start]);
+                text.push_str(value);
+                end = range.end;
+            }
+        }
+        text.push_str(&self.text[end..]);
+        text
+    }
+
+    /// Short preview of the text, shown next to the name.
+    pub fn preview(&self) -> String {
+        const MAX_CHARS: usize = 48;
+        let line = self.text.lines().next().unwrap_or_default();
+        if line.chars().count() > MAX_CHARS || self.text.lines().nth(1).is_some() {
+            let short: String = line.chars().take(MAX_CHARS).collect();
+            format!("{}…", short.trim_end())
+        } else {
+            line.to_string()
+        }
+    }
+}
+
+/// Byte range and trimmed name of every `{{name}}` placeholder in `text`.
+fn placeholders(text: &str) -> Vec<(Range<usize>, &str)> {
+    let mut placeholders = Vec::new();
+    let mut offset = 0;
+    while let Some(start) = text[offset..].find("{{").map(|i| offset + i) {
+        let Some(end) = text[start + 2..].find("}}").map(|i| start + 2 + i + 2) else {
+            break;
+        };
+        placeholders.push((start..end, text[start + 2..end - 2].trim()));
+        offset = end;
+    }
+    placeholders
+}
+
+/// The user's prompt templates, stored in the settings.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PromptLibrary {
+    pub templates: Vec<PromptTemplate>,
+}
+
+impl Default for PromptLibrary {
+    fn default() -> Self {
+        Self {
+            templates: vec![
+                PromptTemplate::new("fact", "Tell me a fun fact about {{topic}}"),
+                PromptTemplate::new(
+                    "snippet",
+                    "Show me a code snippet of {{what}} in {{language}}",
+                ),
+                PromptTemplate::new("joke", "Tell me a joke about {{subject}}"),
+                PromptTemplate::new("gift", "Give me ideas for a birthday present for {{who}}"),
+            ],
+        }
+    }
+}
+
+impl PromptLibrary {
+    /// Templates whose name starts with `query`, ignoring case.
+    pub fn matching<'a>(&'a self, query: &str) -> impl Iterator<Item = &'a PromptTemplate> {
+        let query = query.to_lowercase();
+        self.templates
+            .iter()
+            .filter(move |t| !t.name.is_empty() && t.name.to_lowercase().starts_with(&query))
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        for (i, template) in self.templates.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                let title = if template.name.is_empty() {
+                    "(unnamed)".to_string()
+                } else {
+                    format!("/{}", template.name)
+                };
+                egui::CollapsingHeader::new(title)
+                    .id_salt("template")
+                    .show(ui, |ui| {
+                        ui.horizontal(|ui| {
+                            ui.label("Name");
+                            ui.add(
+                                egui::TextEdit::singleline(&mut template.name)
+                                    .hint_text("summarize"),
+                            );
+                            if ui.button("🗑").on_hover_text("Remove template").clicked() {
+                                remove = Some(i);
+                            }
+                        });
+                        ui.add(
+                            egui::TextEdit::multiline(&mut template.text)
+                                .hint_text("Summarize this text in {{language}}:")
+                                .desired_rows(3)
+                                .desired_width(f32::INFINITY),
+                        );
+                        let variables = template.variables();
+                        if !variables.is_empty() {
+                            ui.weak(format!("Variables: {}", variables.join(", ")));
+                        }
+
+                        let mut has_prompt = template.system_prompt.is_some();
+                        ui.checkbox(&mut has_prompt, "Set a system prompt");
+                        if !has_prompt {
+                            template.system_prompt = None;
+                        } else if template.system_prompt.is_none() {
+                            template.system_prompt = Some(String::new());
+                        }
+                        if let Some(prompt) = &mut template.system_prompt {
+                            ui.add(
+                                egui::TextEdit::multiline(prompt)
+                                    .desired_rows(2)
+                                    .desired_width(f32::INFINITY),
+                            );
+                        }
+
+                        ui.horizontal(|ui| {
+                            ui.label("Model");
+                            egui::ComboBox::from_id_salt("template_model")
+                                .selected_text(
+                                    template
+                                        .model
+                                        .map_or("Keep the chat's model".to_string(), |m| {
+                                            m.to_string()
+                                        }),
+                                )
+                                .height(400.0)
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(
+                                        &mut template.model,
+                                        None,
+                                        "Keep the chat's model",
+                                    );
+                                    crate::models::show_grouped(ui, |ui, model| {
+                                        ui.selectable_value(
+                                            &mut template.model,
+                                            Some(model.model()),
+                                            model.label(),
+                                        )
+                                    });
+                                });
+                        });
+                    });
+            });
+        }
+        if let Some(i) = remove {
+            self.templates.remove(i);
+        }
+        if ui.button("➕ New template").clicked() {
+            self.templates.push(PromptTemplate::default());
+        }
+    }
+}
+
+/// A picked template waiting for its variables to be filled in.
+pub struct TemplateForm {
+    template: PromptTemplate,
+    values: Vec<(String, String)>,
+    focused: bool,
+}
+
+impl TemplateForm {
+    pub fn new(template: PromptTemplate) -> Self {
+        let values = template
+            .variables()
+            .into_iter()
+            .map(|name| (name, String::new()))
+            .collect();
+        Self {
+            template,
+            values,
+            focused: false,
+        }
+    }
+
+    #[inline]
+    pub fn template(&self) -> &PromptTemplate {
+        &self.template
+    }
+
+    pub fn render(&self) -> String {
+        self.template.render(&self.values)
+    }
+
+    /// Returns `Some(true)` once the user confirms and `Some(false)` if they cancel.
+    pub fn show(&mut self, ui: &mut egui::Ui) -> Option<bool> {
+        let mut result = None;
+        ui.horizontal(|ui| {
+            ui.strong(format!("/{}", self.template.name));
+            ui.weak(self.template.preview());
+        });
+        egui::Grid::new("template_variables")
+            .num_columns(2)
+            .show(ui, |ui| {
+                for (i, (name, value)) in self.values.iter_mut().enumerate() {
+                    ui.label(name.as_str());
+                    let resp = ui.add(egui::TextEdit::singleline(value).desired_width(240.0));
+                    if i == 0 && !self.focused {
+                        resp.request_focus();
+                        self.focused = true;
+                    }
+                    if resp.lost_focus() && ui.input(|i| i.key_pressed(egui::Key::Enter)) {
+                        result = Some(true);
+                    }
+                    ui.end_row();
+                }
+            });
+        ui.horizontal(|ui| {
+            if ui.button("✔ Insert").clicked() {
+                result = Some(true);
+            }
+            if ui.button("❌ Cancel").clicked() || ui.input(|i| i.key_pressed(egui::Key::Escape)) {
+                result = Some(false);
+            }
+        });
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn variables_are_trimmed_and_listed_once() {
+        let template = PromptTemplate::new("t", "{{ what }} in {{language}}, then {{what}} again");
+        assert_eq!(template.variables(), ["what", "language"]);
+        assert_eq!(
+            template.render(&values(&[("what", "a loop"), ("language", "Rust")])),
+            "a loop in Rust, then a loop again"
+        );
+    }
+
+    #[test]
+    fn empty_and_unclosed_placeholders_are_kept() {
+        let template = PromptTemplate::new("t", "{{}} and {{topic}} and {{unclosed");
+        assert_eq!(template.variables(), ["topic"]);
+        assert_eq!(
+            template.render(&values(&[("topic", "cats")])),
+            "{{}} and cats and {{unclosed"
+        );
+
+        let template = PromptTemplate::new("t", "Hello {{");
+        assert!(template.variables().is_empty());
+        assert_eq!(template.render(&[]), "Hello {{");
+    }
+
+    #[test]
+    fn missing_values_leave_the_placeholder() {
+        let template = PromptTemplate::new("t", "{{greeting}}, {{name}}!");
+        assert_eq!(
+            template.render(&values(&[("name", "Ada")])),
+            "{{greeting}}, Ada!"
+        );
+    }
+
+    #[test]
+    fn multibyte_text_around_placeholders() {
+        let template = PromptTemplate::new("t", "Übersetze «{{text}}» ins {{язык}} 🌍");
+        assert_eq!(template.variables(), ["text", "язык"]);
+        assert_eq!(
+            template.render(&values(&[("text", "こんにちは"), ("язык", "Deutsche")])),
+            "Übersetze «こんにちは» ins Deutsche 🌍"
+        );
+    }
+}
@@ -0,0 +1,210 @@
+use eframe::egui;
+use gemini_rust::{GenerationResponse, HarmBlockThreshold, HarmCategory, SafetySetting};
+use serde::{Deserialize, Serialize};
+
+/// How strictly the API filters one harm category.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SafetyThreshold {
+    #[default]
+    BlockNone,
+    BlockOnlyHigh,
+    BlockMediumAndAbove,
+    BlockLowAndAbove,
+}
+
+impl SafetyThreshold {
+    pub const ALL: [Self; 4] = [
+        Self::BlockNone,
+        Self::BlockOnlyHigh,
+        Self::BlockMediumAndAbove,
+        Self::BlockLowAndAbove,
+    ];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::BlockNone => "Block none",
+            Self::BlockOnlyHigh => "Block few",
+            Self::BlockMediumAndAbove => "Block some",
+            Self::BlockLowAndAbove => "Block most",
+        }
+    }
+
+    const fn to_gemini(self) -> HarmBlockThreshold {
+        match self {
+            Self::BlockNone => HarmBlockThreshold::BlockNone,
+            Self::BlockOnlyHigh => HarmBlockThreshold::BlockOnlyHigh,
+            Self::BlockMediumAndAbove => HarmBlockThreshold::BlockMediumAndAbove,
+            Self::BlockLowAndAbove => HarmBlockThreshold::BlockLowAndAbove,
+        }
+    }
+}
+
+/// Per-chat safety thresholds. Everything is unblocked by default.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SafetySettings {
+    pub harassment: SafetyThreshold,
+    pub hate_speech: SafetyThreshold,
+    pub sexually_explicit: SafetyThreshold,
+    pub dangerous_content: SafetyThreshold,
+    pub civic_integrity: SafetyThreshold,
+}
+
+impl SafetySettings {
+    fn categories(&mut self) -> [(&'static str, HarmCategory, &mut SafetyThreshold); 5] {
+        [
+            ("Harassment", HarmCategory::Harassment, &mut self.harassment),
+            (
+                "Hate speech",
+                HarmCategory::HateSpeech,
+                &mut self.hate_speech,
+            ),
+            (
+                "Sexually explicit",
+                HarmCategory::SexuallyExplicit,
+                &mut self.sexually_explicit,
+            ),
+            (
+                "Dangerous content",
+                HarmCategory::DangerousContent,
+                &mut self.dangerous_content,
+            ),
+            (
+                "Civic integrity",
+                HarmCategory::CivicIntegrity,
+                &mut self.civic_integrity,
+            ),
+        ]
+    }
+
+    /// Safety settings sent with every request.
+    pub fn to_settings(mut self) -> Vec<SafetySetting> {
+        self.categories()
+            .into_iter()
+            .map(|(_, category, threshold)| SafetySetting {
+                category,
+                threshold: threshold.to_gemini(),
+            })
+            .collect()
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new("safety_settings")
+            .num_columns(2)
+            .show(ui, |ui| {
+                for (label, _, threshold) in self.categories() {
+                    ui.label(label);
+                    egui::ComboBox::from_id_salt(label)
+                        .selected_text(threshold.label())
+                        .show_ui(ui, |ui| {
+                            for value in SafetyThreshold::ALL {
+                                ui.selectable_value(threshold, value, value.label());
+                            }
+                        });
+                    ui.end_row();
+                }
+            });
+    }
+}
+
+/// How the API rated a response (or a prompt) in one harm category.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafetyRating {
+    /// e.g. `HARM_CATEGORY_HARASSMENT`
+    pub category: String,
+    /// e.g. `NEGLIGIBLE`, `LOW`, `MEDIUM` or `HIGH`
+    pub probability: String,
+    pub blocked: bool,
+}
+
+impl SafetyRating {
+    fn from_api(ratings: &[gemini_rust::SafetyRating]) -> Vec<Self> {
+        ratings
+            .iter()
+            .map(|rating| Self {
+                category: api_name(&rating.category),
+                probability: api_name(&rating.probability),
+                blocked: rating.blocked.unwrap_or(false),
+            })
+            .collect()
+    }
+
+    /// Whether the rating is worth showing to the user.
+    pub fn is_notable(&self) -> bool {
+        self.blocked || !matches!(self.probability.as_str(), "NEGLIGIBLE" | "")
+    }
+
+    pub fn describe(&self) -> String {
+        let category = self
+            .category
+            .trim_start_matches("HARM_CATEGORY_")
+            .replace('_', " ")
+            .to_lowercase();
+        let probability = self.probability.to_lowercase();
+        if self.blocked {
+            format!("{category}: {probability} (blocked)")
+        } else {
+            format!("{category}: {probability}")
+        }
+    }
+}
+
+/// Reads the finish reason and safety ratings of the first candidate of a response.
+/// A blocked prompt is reported with a `PROMPT_` prefixed block reason.
+pub fn feedback(response: &GenerationResponse) -> Option<(Option<String>, Vec<SafetyRating>)> {
+    if let Some(feedback) = &response.prompt_feedback {
+        if let Some(reason) = &feedback.block_reason {
+            return Some((
+                Some(format!("PROMPT_{}", api_name(reason))),
+                SafetyRating::from_api(&feedback.safety_ratings),
+            ));
+        }
+    }
+
+    // Other candidates are only kept as alternative answers, see `candidate_count`
+    let candidate = response
+        .candidates
+        .iter()
+        .enumerate()
+        .find(|(position, c)| c.index.map_or(*position, |i| i as usize) == 0)
+        .map(|(_, c)| c)?;
+    let finish_reason = candidate.finish_reason.as_ref().map(api_name);
+    let ratings = candidate
+        .safety_ratings
+        .as_deref()
+        .map(SafetyRating::from_api)
+        .unwrap_or_default();
+    (finish_reason.is_some() || !ratings.is_empty()).then_some((finish_reason, ratings))
+}
+
+/// Name of an API enum value as sent over the wire, e.g. `HARM_CATEGORY_HARASSMENT`.
+/// The names are kept as strings, so that values added to the API are still shown.
+pub fn api_name(value: &impl Serialize) -> String {
+    match serde_json::to_value(value) {
+        Ok(serde_json::Value::String(name)) => name,
+        _ => String::new(),
+    }
+}
+
+/// Explanation of a finish reason, `None` if the response finished normally.
+pub fn describe_finish_reason(reason: &str) -> Option<String> {
+    let text = match reason {
+        "STOP" | "FINISH_REASON_UNSPECIFIED" => return None,
+        "MAX_TOKENS" => "✂ Cut off: the maximum number of output tokens was reached",
+        "SAFETY" => "🛡 Stopped by the safety filters",
+        "RECITATION" => "📚 Stopped: the response was too close to existing content",
+        "LANGUAGE" => "🌐 Stopped: the response language is not supported",
+        "BLOCKLIST" => "🚫 Stopped: the response contained blocked terms",
+        "PROHIBITED_CONTENT" => "🚫 Stopped: the response may contain prohibited content",
+        "SPII" => "🔒 Stopped: the response may contain sensitive personal information",
+        "MALFORMED_FUNCTION_CALL" => "⚠ Stopped: the model produced an invalid function call",
+        "IMAGE_SAFETY" => "🛡 Stopped: a generated image was flagged by the safety filters",
+        reason => {
+            return Some(match reason.strip_prefix("PROMPT_") {
+                Some(block) => format!("🛡 The prompt was blocked ({block})"),
+                None => format!("⚠ Stopped: {reason}"),
+            })
+        }
+    };
+    Some(text.to_string())
+}
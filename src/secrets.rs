@@ -0,0 +1,71 @@
+use serde::{Deserialize, Serialize};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+/// API keys and tokens. They are left out of the app state and the settings exports,
+/// and kept in `secrets.json` in the storage directory, readable by the user only.
+#[derive(Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Secrets {
+    pub api_key: String,
+    /// Keys of `Settings::api_keys` by profile name, in the same order.
+    pub api_keys: Vec<(String, String)>,
+    pub oauth_token: String,
+}
+
+impl Secrets {
+    fn path() -> Option<PathBuf> {
+        eframe::storage_dir(crate::TITLE).map(|p| p.join("secrets.json"))
+    }
+
+    pub fn load() -> Self {
+        let Some(path) = Self::path().filter(|p| p.exists()) else {
+            return Self::default();
+        };
+        std::fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|bytes| Ok(serde_json::from_slice(&bytes)?))
+            .unwrap_or_else(|e| {
+                log::error!("failed to read the secrets file: {e}");
+                Self::default()
+            })
+    }
+
+    pub fn save(&self) {
+        let Some(path) = Self::path() else {
+            return;
+        };
+        let result = path
+            .parent()
+            .map_or(Ok(()), std::fs::create_dir_all)
+            .and_then(|_| write_private(&path, &serde_json::to_vec(self).unwrap_or_default()));
+        if let Err(e) = result {
+            log::error!("failed to save the secrets file: {e}");
+        }
+    }
+
+    /// Every non-empty secret, to keep them out of the logs.
+    pub fn values(&self) -> impl Iterator<Item = &str> {
+        std::iter::once(self.api_key.as_str())
+            .chain(self.api_keys.iter().map(|(_, key)| key.as_str()))
+            .chain(std::iter::once(self.oauth_token.as_str()))
+            .filter(|s| !s.is_empty())
+    }
+}
+
+/// Writes a file only the current user can read.
+fn write_private(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    // The mode is only applied to new files
+    #[cfg(unix)]
+    file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    file.write_all(bytes)
+}
@@ -1,7 +1,10 @@
 use crate::{
     chat::{Chat, ChatAction, ChatExportFormat},
+    config::Field,
     file_handler::Attachment,
-    widgets::{ModelPicker, RequestInfoType, Settings},
+    models::Capabilities,
+    presets::Preset,
+    widgets::{ApiKeyProfile, GeminiModel, ModelPicker, RequestInfoType, Settings},
 };
 use eframe::egui::{self, vec2, Color32, CornerRadius, Frame, Layout, Stroke};
 use egui_commonmark::CommonMarkCache;
@@ -18,6 +21,7 @@ use std::{
     hash::{Hash, Hasher},
     path::PathBuf,
     rc::Rc,
+    sync::atomic::Ordering,
     time::{Duration, Instant},
 };
 #[cfg(feature = "tts")]
@@ -47,6 +51,7 @@ enum BackendResponse {
         token: String,
         projects: Vec<String>,
     },
+    Preset(Box<Preset>),
 }
 
 // <progress, response, error>
@@ -81,6 +86,9 @@ pub struct Sessions {
     pub settings: Settings,
     #[serde(default = "default_true")]
     left_panel_visible: bool,
+    /// Whether an outdated model list was already refreshed in this run.
+    #[serde(skip)]
+    models_checked: bool,
 }
 
 fn default_true() -> bool {
@@ -154,6 +162,7 @@ impl Default for Sessions {
             settings_open: false,
             settings: Settings::default(),
             left_panel_visible: true,
+            models_checked: false,
         }
     }
 }
@@ -187,6 +196,29 @@ async fn login_google(handle: &BackendFlowerHandle) {
     }
 }
 
+async fn refresh_models(handle: &BackendFlowerHandle, key_profile: ApiKeyProfile, notify: bool) {
+    match crate::models::fetch(key_profile).await {
+        Ok(models) => {
+            let count = models.len();
+            log::info!("fetched {count} models");
+            crate::models::catalogue_mut().set_fetched(models);
+            handle.success(if notify {
+                BackendResponse::Toast(Toast::info(format!("Fetched {count} models")))
+            } else {
+                BackendResponse::Ignore
+            });
+        }
+        Err(e) if notify => {
+            log::error!("failed to fetch the model list: {e}");
+            handle.success(BackendResponse::Ignore);
+        }
+        Err(e) => {
+            log::info!("failed to refresh the model list: {e}");
+            handle.success(BackendResponse::Ignore);
+        }
+    }
+}
+
 async fn pick_files(id: usize, handle: &BackendFlowerHandle) {
     let Some(files) = rfd::AsyncFileDialog::new()
         .add_filter(
@@ -245,6 +277,56 @@ async fn load_settings(handle: &BackendFlowerHandle) {
     }
 }
 
+/// Whether the model can read the attachment, tells the user if it can't.
+fn check_attachment(
+    attachment: &Attachment,
+    model: GeminiModel,
+    capabilities: &Capabilities,
+    toasts: &mut Toasts,
+) -> bool {
+    if capabilities.accepts(&attachment.mime) {
+        return true;
+    }
+    log::info!(
+        "{model} doesn't accept `{}` ({})",
+        attachment.path.display(),
+        attachment.mime
+    );
+    let filename = attachment.path.file_name().unwrap_or_default();
+    toasts.add(Toast::info(format!(
+        "Skipping `{}`: {model} doesn't accept {} files",
+        filename.to_string_lossy(),
+        attachment.mime
+    )));
+    false
+}
+
+async fn import_preset(handle: &BackendFlowerHandle) {
+    let Some(file) = rfd::AsyncFileDialog::new()
+        .add_filter("JSON file", &["json"])
+        .pick_file()
+        .await
+    else {
+        handle.success(BackendResponse::Toast(Toast::info("No file selected")));
+        return;
+    };
+
+    log::info!("reading preset from `{}`", file.path().display());
+    let preset = std::fs::read(file.path())
+        .map_err(anyhow::Error::from)
+        .and_then(|bytes| Ok(serde_json::from_slice::<Preset>(&bytes)?));
+    match preset {
+        Ok(mut preset) => {
+            preset.clear_local_paths();
+            handle.success(BackendResponse::Preset(Box::new(preset)));
+        }
+        Err(e) => {
+            log::error!("failed to load preset: {e}");
+            handle.success(BackendResponse::Toast(Toast::error(e.to_string())));
+        }
+    }
+}
+
 fn preview_files_being_dropped(ctx: &egui::Context) {
     use egui::*;
     use std::fmt::Write as _;
@@ -280,6 +362,21 @@ fn preview_files_being_dropped(ctx: &egui::Context) {
 }
 
 impl Sessions {
+    /// Fetches the model list in the background. Failures are only reported if `notify` is set.
+    fn refresh_models(&self, notify: bool) {
+        let Some(key_profile) = self.settings.key_profile(None) else {
+            if notify {
+                log::warn!("An API key is needed to fetch the model list");
+            }
+            return;
+        };
+        let handle = self.flower.handle();
+        tokio::spawn(async move {
+            handle.activate();
+            refresh_models(&handle, key_profile, notify).await;
+        });
+    }
+
     pub fn show(&mut self, ctx: &egui::Context) {
         // check if tts stopped speaking
         #[cfg(feature = "tts")]
@@ -329,12 +426,17 @@ impl Sessions {
             Modal::new(ctx, "global_settings_modal").with_close_on_outside_click(true);
 
         // poll all flowers
-        for chat in self.chats.iter_mut() {
+        for (i, chat) in self.chats.iter_mut().enumerate() {
             if chat.flower_active() {
                 request_repaint = true;
                 chat.poll_flower(&mut chat_modal);
+                if !chat.flower_active() && i != self.selected_chat {
+                    chat.unread = true;
+                }
             }
+            request_repaint |= chat.poll_comparison(&mut chat_modal);
         }
+        request_repaint |= self.start_queued_chats();
         if self.flower.is_active() {
             request_repaint = true;
             self.poll_backend_flower(&modal);
@@ -372,8 +474,23 @@ impl Sessions {
 
                     if let Some(chat) = self.chats.get(self.selected_chat) { // TODO!
                         let count = chat.token_count.unwrap_or(0);
-                        ui.label(format!("{} tokens", count))
-                            .on_hover_text("Estimated total tokens in context");
+                        let limit = chat.model_picker.capabilities().input_token_limit;
+                        ui.label(format!("{} tokens", count)).on_hover_text(format!(
+                            "Estimated total tokens in context, out of {limit} for {}",
+                            chat.model_picker.selected
+                        ));
+                        if let Some(cache) = chat
+                            .context_cache
+                            .as_ref()
+                            .filter(|c| !c.is_expired())
+                        {
+                            let expires = cache.expires_at.with_timezone(&chrono::Local);
+                            ui.weak("🗄 cached").on_hover_text(format!(
+                                "System prompt, tools and {} pinned attachment(s) are cached until {}",
+                                cache.files,
+                                expires.format("%H:%M")
+                            ));
+                        }
                         ui.separator();
                     }
                 });
@@ -400,6 +517,7 @@ impl Sessions {
                 egui::ScrollArea::both().auto_shrink(false).show(ui, |ui| {
                     let mut selected_project = None;
                     let mut should_logout = false;
+                    let mut should_refresh_models = false;
                     self.settings.show(
                         ui,
                         &mut |typ| match typ {
@@ -423,12 +541,25 @@ impl Sessions {
                             RequestInfoType::SelectProject(proj) => {
                                 selected_project = Some(proj);
                             }
+                            RequestInfoType::RefreshModels => {
+                                should_refresh_models = true;
+                            }
+                            RequestInfoType::ImportPreset => {
+                                let handle = self.flower.handle();
+                                tokio::spawn(async move {
+                                    handle.activate();
+                                    import_preset(&handle).await;
+                                });
+                            }
                         },
                         &settings_modal,
                     );
                     if let Some(proj) = selected_project {
                         self.settings.project_id = proj;
                     }
+                    if should_refresh_models {
+                        self.refresh_models(true);
+                    }
                     if should_logout {
                         use gemini_code_assist_adapter::auth::GoogleAuthManager;
                         GoogleAuthManager::new().clear_token_cache();
@@ -455,6 +586,13 @@ impl Sessions {
             preview_files_being_dropped(ctx);
         }
 
+        if !self.models_checked && !self.settings.api_key.is_empty() {
+            self.models_checked = true;
+            if crate::models::catalogue().is_stale() {
+                self.refresh_models(false);
+            }
+        }
+
         // Token counting logic
         if let Some(chat) = self.chats.get_mut(self.selected_chat) {
             let mut hasher = std::collections::hash_map::DefaultHasher::new();
@@ -475,11 +613,15 @@ impl Sessions {
                     .last_token_check
                     .map_or(true, |t| t.elapsed() > Duration::from_secs(1));
 
-            if should_update && !self.settings.api_key.is_empty() {
+            let key_profile = should_update
+                .then(|| self.settings.key_profile(chat.key_profile.as_deref()))
+                .flatten();
+            if let Some(key_profile) = key_profile {
                 chat.last_content_hash = current_hash;
                 chat.last_token_check = Some(Instant::now());
 
                 let chat_id = chat.id();
+                let system_prompt = chat.model_picker.system_instruction();
                 let messages = chat.messages.clone();
                 let chatbox = chat.chatbox.clone();
                 let files = chat.files.clone();
@@ -487,10 +629,7 @@ impl Sessions {
                 let settings = self.settings.clone();
 
                 tokio::spawn(async move {
-                    if let Ok(client) = settings
-                        .model_picker
-                        .create_client(&settings.api_key, settings.proxy_path)
-                    {
+                    if let Ok(client) = settings.model_picker.create_client(&key_profile) {
                         if let Ok(contents) = crate::chat_completion::build_history(
                             &client,
                             &messages,
@@ -502,6 +641,9 @@ impl Sessions {
                         {
                             let mut builder = client.generate_content();
                             builder.contents.extend(contents);
+                            if let Some(prompt) = system_prompt {
+                                builder = builder.with_system_prompt(prompt);
+                            }
 
                             if let Ok(resp) = builder.count_tokens().await {
                                 handle.activate();
@@ -531,6 +673,8 @@ impl Sessions {
             return;
         };
 
+        let model = chat.model_picker.selected;
+        let capabilities = chat.model_picker.capabilities();
         ctx.input(|i| {
             for file in &i.raw.dropped_files {
                 if let Some(path) = &file.path {
@@ -561,7 +705,10 @@ impl Sessions {
                         )));
                         continue;
                     }
-                    chat.files.push(Attachment::from_path(path.clone()));
+                    let attachment = Attachment::from_path(path.clone());
+                    if check_attachment(&attachment, model, &capabilities, &mut self.toasts) {
+                        chat.attach_files([attachment]);
+                    }
                 }
             }
         });
@@ -657,9 +804,25 @@ impl Sessions {
                     return;
                 };
 
-                chat.model_picker.show(ui, &mut |_| {});
+                if !self.settings.api_keys.is_empty() {
+                    let mut key_profile = chat.key_profile.clone();
+                    egui::ComboBox::from_label("API key")
+                        .selected_text(key_profile.as_deref().unwrap_or(ApiKeyProfile::DEFAULT))
+                        .show_ui(ui, |ui| {
+                            ui.selectable_value(&mut key_profile, None, ApiKeyProfile::DEFAULT);
+                            for name in self.settings.key_names().into_iter().skip(1) {
+                                ui.selectable_value(&mut key_profile, Some(name.to_string()), name);
+                            }
+                        });
+                    chat.set_key_profile(key_profile);
+                }
+
+                self.settings.presets.show_bar(ui, &mut chat.model_picker);
+                chat.model_picker.show(ui, None, &mut |_| {});
 
-                if self.settings.inherit_chat_picker {
+                if self.settings.inherit_chat_picker
+                    && !self.settings.overrides.is_locked(Field::Model)
+                {
                     self.settings.model_picker.selected = chat.model_picker.selected.clone();
                 }
             });
@@ -739,12 +902,26 @@ impl Sessions {
                 Ok(BackendResponse::Files { id, files }) => {
                     if let Some(chat) = self.chats.iter_mut().find(|c| c.id() == id) {
                         log::debug!("adding {} file(s) to chat {}", files.len(), id);
-                        chat.files
-                            .extend(files.into_iter().map(Attachment::from_path));
+                        let model = chat.model_picker.selected;
+                        let capabilities = chat.model_picker.capabilities();
+                        let files: Vec<_> = files
+                            .into_iter()
+                            .map(Attachment::from_path)
+                            .filter(|file| {
+                                check_attachment(file, model, &capabilities, &mut self.toasts)
+                            })
+                            .collect();
+                        chat.attach_files(files);
                     }
                 }
                 Ok(BackendResponse::Settings(settings)) => {
+                    // Exported settings have no secrets unless the user included them
+                    let secrets = self.settings.secrets();
+                    let overrides = std::mem::take(&mut self.settings.overrides);
                     self.settings = *settings;
+                    self.settings.restore_secrets(secrets);
+                    overrides.apply(&mut self.settings);
+                    self.settings.hide_secrets_in_logs();
                 }
                 Ok(BackendResponse::TokenCount { chat_id, count }) => {
                     if let Some(chat) = self.chats.iter_mut().find(|c| c.id() == chat_id) {
@@ -753,6 +930,7 @@ impl Sessions {
                 }
                 Ok(BackendResponse::AuthResult { token, projects }) => {
                     self.settings.oauth_token = token;
+                    self.settings.hide_secrets_in_logs();
                     self.settings.available_projects = projects;
                     if self.settings.project_id.is_empty()
                         && !self.settings.available_projects.is_empty()
@@ -761,6 +939,11 @@ impl Sessions {
                     }
                     self.toasts.add(Toast::success("Google Login successful!"));
                 }
+                Ok(BackendResponse::Preset(preset)) => {
+                    let name = &self.settings.presets.add(*preset).name;
+                    self.toasts
+                        .add(Toast::success(format!("Imported preset \"{name}\"")));
+                }
                 Err(flowync::error::Compact::Suppose(e)) => {
                     modal
                         .dialog()
@@ -782,12 +965,87 @@ impl Sessions {
         });
     }
 
+    /// Starts queued requests while there are free generation slots.
+    /// Returns whether any chat is still waiting.
+    fn start_queued_chats(&mut self) -> bool {
+        let mut running: usize = self.chats.iter().map(Chat::running_generations).sum();
+        let mut waiting = false;
+        for (idx, column) in self.queue() {
+            let chat = &mut self.chats[idx];
+            let chat = match column {
+                Some(column) => match chat.compare.as_mut().and_then(|c| c.column_mut(column)) {
+                    Some(chat) => chat,
+                    None => continue,
+                },
+                None => chat,
+            };
+            if chat.stop_generating.swap(false, Ordering::SeqCst) {
+                chat.cancel_queued();
+            } else if self
+                .settings
+                .max_concurrent_generations
+                .is_none_or(|limit| running < limit)
+            {
+                chat.start_queued(&self.settings);
+                running += 1;
+            } else {
+                waiting = true;
+            }
+        }
+        waiting
+    }
+
+    /// Chats and comparison columns waiting for a free generation slot, oldest first.
+    /// Each entry is the index of a chat, with the index of the column for a comparison.
+    fn queue(&self) -> Vec<(usize, Option<usize>)> {
+        let mut queue = Vec::new();
+        for (i, chat) in self.chats.iter().enumerate() {
+            if let Some(queued_at) = chat.queued_at {
+                queue.push((queued_at, i, None));
+            }
+            if let Some(compare) = &chat.compare {
+                queue.extend(compare.queued().map(|(column, at)| (at, i, Some(column))));
+            }
+        }
+        queue.sort_by_key(|&(queued_at, ..)| queued_at);
+        queue
+            .into_iter()
+            .map(|(_, i, column)| (i, column))
+            .collect()
+    }
+
+    /// Selects the next chat with an unread response, after the current one.
+    fn select_next_unread(&mut self) {
+        let len = self.chats.len();
+        if let Some(idx) = (1..=len)
+            .map(|offset| (self.selected_chat + offset) % len)
+            .find(|&i| self.chats[i].unread)
+        {
+            self.select_chat(idx);
+            self.settings_open = false;
+            self.edited_chat = None;
+        }
+    }
+
     #[inline]
     fn add_default_chat(&mut self) {
+        let mut model_picker = self
+            .settings
+            .presets
+            .default_picker()
+            .unwrap_or(self.model_picker())
+            .clone();
+        // The managed default model also applies over the default preset
+        if let Some(model) = self.settings.overrides.model() {
+            model_picker.selected = model;
+        }
+        self.add_chat(model_picker);
+    }
+
+    fn add_chat(&mut self, model_picker: ModelPicker) {
         // Find the highest existing ID to avoid collisions
         let max_id = self.chats.iter().map(|c| c.id()).max().unwrap_or(0);
-        self.chats
-            .push(Chat::new(max_id + 1, self.model_picker().clone()));
+        self.chats.push(Chat::new(max_id + 1, model_picker));
     }
 
     fn remove_chat(&mut self, idx: usize) {
@@ -802,6 +1060,33 @@ impl Sessions {
 
     fn select_chat(&mut self, idx: usize) {
         self.selected_chat = idx;
+        if let Some(chat) = self.chats.get_mut(idx) {
+            chat.unread = false;
+        }
+    }
+
+    /// Spinner, queue position, error mark or unread dot for a chat in the side panel.
+    fn show_chat_badge(&self, ui: &mut egui::Ui, idx: usize) {
+        let chat = &self.chats[idx];
+        if chat.flower_active() {
+            ui.add(egui::Spinner::new().size(12.0))
+                .on_hover_text("Generating...");
+        } else if chat.queued_at.is_some() {
+            let position = self
+                .queue()
+                .iter()
+                .position(|&(i, _)| i == idx)
+                .unwrap_or(0)
+                + 1;
+            ui.weak(format!("⏳{position}"))
+                .on_hover_text(format!("Waiting in queue (position {position})"));
+        } else if chat.has_error() {
+            ui.colored_label(ui.visuals().error_fg_color, "⚠")
+                .on_hover_text("Generation failed");
+        } else if chat.unread {
+            ui.colored_label(ui.visuals().selection.bg_fill, "⏺")
+                .on_hover_text("New response");
+        }
     }
 
     /// Returns whether any chat was removed
@@ -867,6 +1152,8 @@ impl Sessions {
                         Some(idx)
                     };
                 }
+                ui.add_space(4.0);
+                self.show_chat_badge(ui, idx);
             });
         });
 
@@ -918,16 +1205,39 @@ impl Sessions {
 
     fn show_chats(&mut self, ui: &mut egui::Ui, modal: &Modal) {
         ui.vertical_centered_justified(|ui| {
-            if ui
+            let resp = ui
                 .add(egui::Button::new("➕ New Chat").min_size(vec2(0.0, 24.0)))
-                .on_hover_text("Create a new chat")
-                .clicked()
-            {
-                self.add_default_chat();
+                .on_hover_text("Create a new chat. Right-click to start from a preset");
+            let mut preset_picker = None;
+            resp.context_menu(|ui| {
+                if self.settings.presets.presets.is_empty() {
+                    ui.weak("No presets yet");
+                }
+                for preset in &self.settings.presets.presets {
+                    if ui.button(&preset.name).clicked() {
+                        preset_picker = Some(preset.model_picker.clone());
+                    }
+                }
+            });
+            if resp.clicked() || preset_picker.is_some() {
+                match preset_picker {
+                    Some(model_picker) => self.add_chat(model_picker),
+                    None => self.add_default_chat(),
+                }
                 self.select_chat(self.chats.len() - 1);
                 self.edited_chat = None;
                 self.settings_open = false;
             }
+
+            let unread = self.chats.iter().filter(|c| c.unread).count();
+            if unread > 0
+                && ui
+                    .button(format!("➡ Next finished chat ({unread})"))
+                    .on_hover_text("Jump to the next chat with a new response")
+                    .clicked()
+            {
+                self.select_next_unread();
+            }
         });
 
         ui.add_space(2.0);
@@ -0,0 +1,453 @@
+use eframe::egui::{self, RichText};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// MIME type the model is asked to answer with.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ResponseFormat {
+    #[default]
+    Text,
+    Json,
+    Enum,
+}
+
+impl ResponseFormat {
+    pub const ALL: [Self; 3] = [Self::Text, Self::Json, Self::Enum];
+
+    pub const fn mime_type(self) -> &'static str {
+        match self {
+            Self::Text => "text/plain",
+            Self::Json => "application/json",
+            Self::Enum => "text/x.enum",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FieldType {
+    #[default]
+    String,
+    Number,
+    Integer,
+    Boolean,
+    StringList,
+}
+
+impl FieldType {
+    pub const ALL: [Self; 5] = [
+        Self::String,
+        Self::Number,
+        Self::Integer,
+        Self::Boolean,
+        Self::StringList,
+    ];
+
+    const fn label(self) -> &'static str {
+        match self {
+            Self::String => "string",
+            Self::Number => "number",
+            Self::Integer => "integer",
+            Self::Boolean => "boolean",
+            Self::StringList => "list of strings",
+        }
+    }
+
+    fn schema(self) -> Value {
+        match self {
+            Self::String => json!({ "type": "string" }),
+            Self::Number => json!({ "type": "number" }),
+            Self::Integer => json!({ "type": "integer" }),
+            Self::Boolean => json!({ "type": "boolean" }),
+            Self::StringList => json!({ "type": "array", "items": { "type": "string" } }),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SchemaField {
+    pub name: String,
+    pub kind: FieldType,
+    pub description: String,
+    pub required: bool,
+}
+
+/// Response format and schema of a chat.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StructuredOutput {
+    pub format: ResponseFormat,
+    /// Use the pasted schema instead of the field list.
+    pub use_raw_schema: bool,
+    pub fields: Vec<SchemaField>,
+    pub raw_schema: String,
+    /// Allowed answers in enum mode, one per line.
+    pub enum_values: String,
+}
+
+impl StructuredOutput {
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.format != ResponseFormat::Text
+    }
+
+    /// MIME type to put in the generation config, if it differs from plain text.
+    pub fn mime_type(&self) -> Option<String> {
+        self.is_enabled()
+            .then(|| self.format.mime_type().to_string())
+    }
+
+    /// Schema to put in the generation config. Invalid pasted schemas are ignored.
+    pub fn schema(&self) -> Option<Value> {
+        match self.format {
+            ResponseFormat::Text => None,
+            ResponseFormat::Enum => {
+                let values: Vec<&str> = self
+                    .enum_values
+                    .lines()
+                    .map(str::trim)
+                    .filter(|v| !v.is_empty())
+                    .collect();
+                (!values.is_empty()).then(|| json!({ "type": "string", "enum": values }))
+            }
+            ResponseFormat::Json if self.use_raw_schema => {
+                if self.raw_schema.trim().is_empty() {
+                    return None;
+                }
+                serde_json::from_str(&self.raw_schema)
+                    .map_err(|e| log::warn!("ignoring invalid response schema: {e}"))
+                    .ok()
+            }
+            ResponseFormat::Json => {
+                let fields: Vec<_> = self
+                    .fields
+                    .iter()
+                    .filter(|f| !f.name.trim().is_empty())
+                    .collect();
+                if fields.is_empty() {
+                    return None;
+                }
+
+                let mut properties = Map::new();
+                for field in &fields {
+                    let mut schema = field.kind.schema();
+                    if !field.description.is_empty() {
+                        schema["description"] = json!(field.description);
+                    }
+                    properties.insert(field.name.trim().to_string(), schema);
+                }
+                let required: Vec<&str> = fields
+                    .iter()
+                    .filter(|f| f.required)
+                    .map(|f| f.name.trim())
+                    .collect();
+                Some(json!({
+                    "type": "object",
+                    "properties": properties,
+                    "required": required,
+                }))
+            }
+        }
+    }
+
+    /// Checks a finished response against the configured format and schema.
+    /// Returns the list of problems, empty if the response is valid.
+    pub fn validate(&self, text: &str) -> Vec<String> {
+        match self.format {
+            ResponseFormat::Text => Vec::new(),
+            ResponseFormat::Enum => match self.schema() {
+                Some(schema) => validate(&schema, &Value::String(text.trim().to_string()), "$"),
+                None => Vec::new(),
+            },
+            ResponseFormat::Json => match parse_json(text) {
+                Ok(value) => self
+                    .schema()
+                    .map(|schema| validate(&schema, &value, "$"))
+                    .unwrap_or_default(),
+                Err(e) => vec![format!("Response is not valid JSON: {e}")],
+            },
+        }
+    }
+
+    pub fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("Response type");
+            egui::ComboBox::from_id_salt("response_format")
+                .selected_text(self.format.mime_type())
+                .show_ui(ui, |ui| {
+                    for format in ResponseFormat::ALL {
+                        ui.selectable_value(&mut self.format, format, format.mime_type());
+                    }
+                });
+        });
+
+        match self.format {
+            ResponseFormat::Text => {}
+            ResponseFormat::Enum => {
+                ui.label("Allowed answers, one per line:");
+                ui.add(
+                    egui::TextEdit::multiline(&mut self.enum_values)
+                        .desired_rows(3)
+                        .desired_width(f32::INFINITY),
+                );
+            }
+            ResponseFormat::Json => {
+                ui.horizontal(|ui| {
+                    ui.selectable_value(&mut self.use_raw_schema, false, "Fields");
+                    ui.selectable_value(&mut self.use_raw_schema, true, "JSON Schema");
+                });
+                if self.use_raw_schema {
+                    self.show_raw_schema(ui);
+                } else {
+                    self.show_fields(ui);
+                }
+            }
+        }
+    }
+
+    fn show_raw_schema(&mut self, ui: &mut egui::Ui) {
+        ui.add(
+            egui::TextEdit::multiline(&mut self.raw_schema)
+                .code_editor()
+                .hint_text(r#"{ "type": "object", "properties": { ... } }"#)
+                .desired_rows(6)
+                .desired_width(f32::INFINITY),
+        );
+        if !self.raw_schema.trim().is_empty() {
+            if let Err(e) = serde_json::from_str::<Value>(&self.raw_schema) {
+                ui.colored_label(ui.visuals().error_fg_color, format!("⚠ {e}"));
+            }
+        }
+    }
+
+    fn show_fields(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        for (i, field) in self.fields.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                ui.horizontal(|ui| {
+                    ui.add(
+                        egui::TextEdit::singleline(&mut field.name)
+                            .hint_text("name")
+                            .desired_width(90.0),
+                    );
+                    egui::ComboBox::from_id_salt("field_type")
+                        .selected_text(field.kind.label())
+                        .show_ui(ui, |ui| {
+                            for kind in FieldType::ALL {
+                                ui.selectable_value(&mut field.kind, kind, kind.label());
+                            }
+                        });
+                    ui.checkbox(&mut field.required, "")
+                        .on_hover_text("Required");
+                    if ui.small_button("🗑").on_hover_text("Remove field").clicked() {
+                        remove = Some(i);
+                    }
+                });
+                ui.add(
+                    egui::TextEdit::singleline(&mut field.description)
+                        .hint_text("description (optional)")
+                        .desired_width(f32::INFINITY),
+                );
+            });
+        }
+        if let Some(i) = remove {
+            self.fields.remove(i);
+        }
+        if ui.button("➕ Add field").clicked() {
+            self.fields.push(SchemaField {
+                required: true,
+                ..Default::default()
+            });
+        }
+    }
+}
+
+/// Parses a JSON response, tolerating a surrounding markdown code fence.
+pub fn parse_json(text: &str) -> serde_json::Result<Value> {
+    let text = text.trim();
+    let text = text
+        .strip_prefix("```json")
+        .or_else(|| text.strip_prefix("```"))
+        .and_then(|t| t.strip_suffix("```"))
+        .unwrap_or(text);
+    serde_json::from_str(text)
+}
+
+/// Minimal JSON Schema check covering what the Gemini response schema supports:
+/// `type`, `enum`, `properties`, `required` and `items`.
+fn validate(schema: &Value, value: &Value, path: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+
+    if let Some(expected) = schema.get("type").and_then(Value::as_str) {
+        let matches = match expected.to_lowercase().as_str() {
+            "object" => value.is_object(),
+            "array" => value.is_array(),
+            "string" => value.is_string(),
+            "number" => value.is_number(),
+            "integer" => value.is_i64() || value.is_u64(),
+            "boolean" => value.is_boolean(),
+            "null" => value.is_null(),
+            _ => true,
+        };
+        let nullable = schema.get("nullable").and_then(Value::as_bool) == Some(true);
+        if !matches && !(nullable && value.is_null()) {
+            errors.push(format!(
+                "{path}: expected {expected}, got {}",
+                type_name(value)
+            ));
+            return errors;
+        }
+    }
+
+    if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+        if !allowed.contains(value) {
+            errors.push(format!("{path}: {value} is not one of the allowed values"));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for key in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(key) {
+                    errors.push(format!("{path}: missing required field `{key}`"));
+                }
+            }
+        }
+        if let Some(properties) = schema.get("properties").and_then(Value::as_object) {
+            for (key, property) in properties {
+                if let Some(field) = object.get(key) {
+                    errors.extend(validate(property, field, &format!("{path}.{key}")));
+                }
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (i, item) in array.iter().enumerate() {
+            errors.extend(validate(items, item, &format!("{path}[{i}]")));
+        }
+    }
+
+    errors
+}
+
+const fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Collapsible tree view of a JSON value.
+pub fn show_json_tree(ui: &mut egui::Ui, key: &str, value: &Value, id: egui::Id) {
+    match value {
+        Value::Object(object) => {
+            egui::CollapsingHeader::new(format!("{key} {{{}}}", object.len()))
+                .id_salt(id)
+                .default_open(true)
+                .show(ui, |ui| {
+                    for (k, v) in object {
+                        show_json_tree(ui, k, v, id.with(k));
+                    }
+                });
+        }
+        Value::Array(array) => {
+            egui::CollapsingHeader::new(format!("{key} [{}]", array.len()))
+                .id_salt(id)
+                .default_open(true)
+                .show(ui, |ui| {
+                    for (i, v) in array.iter().enumerate() {
+                        show_json_tree(ui, &i.to_string(), v, id.with(i));
+                    }
+                });
+        }
+        _ => {
+            ui.horizontal(|ui| {
+                ui.label(RichText::new(format!("{key}:")).strong());
+                ui.label(RichText::new(value.to_string()).monospace());
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json_output(fields: &[(&str, FieldType, bool)]) -> StructuredOutput {
+        StructuredOutput {
+            format: ResponseFormat::Json,
+            fields: fields
+                .iter()
+                .map(|&(name, kind, required)| SchemaField {
+                    name: name.to_string(),
+                    kind,
+                    required,
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_json_strips_code_fences() {
+        let expected = json!({ "name": "Ada", "age": 36 });
+        let fenced = "```json\n{\"name\": \"Ada\", \"age\": 36}\n```";
+        assert_eq!(parse_json(fenced).unwrap(), expected);
+        let bare_fence = "```\n{\"name\": \"Ada\", \"age\": 36}\n```";
+        assert_eq!(parse_json(bare_fence).unwrap(), expected);
+        assert_eq!(
+            parse_json(" {\"name\": \"Ada\", \"age\": 36} ").unwrap(),
+            expected
+        );
+        assert!(parse_json("```json\nnot json\n```").is_err());
+    }
+
+    #[test]
+    fn validate_reports_missing_required_fields() {
+        let output = json_output(&[
+            ("name", FieldType::String, true),
+            ("nickname", FieldType::String, false),
+        ]);
+        assert!(output
+            .validate("```json\n{\"name\": \"Ada\"}\n```")
+            .is_empty());
+        assert_eq!(
+            output.validate("{\"nickname\": \"Ada\"}"),
+            ["$: missing required field `name`"]
+        );
+    }
+
+    #[test]
+    fn validate_tells_integers_from_numbers() {
+        let output = json_output(&[
+            ("count", FieldType::Integer, true),
+            ("ratio", FieldType::Number, true),
+        ]);
+        assert!(output.validate("{\"count\": 3, \"ratio\": 3}").is_empty());
+        assert!(output.validate("{\"count\": 3, \"ratio\": 0.5}").is_empty());
+        assert_eq!(
+            output.validate("{\"count\": 3.5, \"ratio\": 0.5}"),
+            ["$.count: expected integer, got number"]
+        );
+    }
+
+    #[test]
+    fn validate_rejects_invalid_json_and_unknown_enum_values() {
+        let output = json_output(&[("name", FieldType::String, true)]);
+        assert_eq!(output.validate("{\"name\": ").len(), 1);
+
+        let answers = StructuredOutput {
+            format: ResponseFormat::Enum,
+            enum_values: "yes\nno\n".to_string(),
+            ..Default::default()
+        };
+        assert!(answers.validate(" yes\n").is_empty());
+        assert_eq!(answers.validate("maybe").len(), 1);
+    }
+}
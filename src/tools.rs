@@ -0,0 +1,277 @@
+use anyhow::{anyhow, bail, Result};
+use gemini_rust::{FunctionDeclaration, Tool as GeminiTool};
+use serde_json::{json, Value};
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{Arc, LazyLock},
+};
+
+/// All tools that can be enabled in a chat.
+pub static REGISTRY: LazyLock<ToolRegistry> = LazyLock::new(ToolRegistry::builtin);
+
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = Result<Value>> + Send + 'a>>;
+
+/// A local function the model is allowed to call.
+pub trait Tool: Send + Sync {
+    /// Name of the function, as seen by the model.
+    fn name(&self) -> &'static str;
+
+    /// Short explanation of what the function does and when to use it.
+    fn description(&self) -> &'static str;
+
+    /// JSON schema of the arguments object.
+    fn parameters(&self) -> Value;
+
+    /// Whether the user has to allow each call, e.g. for tools reading local files.
+    fn needs_confirmation(&self) -> bool {
+        false
+    }
+
+    fn execute<'a>(&'a self, args: Value, scope: &'a ToolScope) -> ToolFuture<'a>;
+}
+
+/// Directories the file tools of a chat may access, chosen by the user.
+#[derive(Debug, Clone, Default)]
+pub struct ToolScope {
+    pub roots: Vec<PathBuf>,
+}
+
+impl ToolScope {
+    /// Resolves `path` and checks that it lies within one of the roots, and outside
+    /// of the directories holding the app's data and keys.
+    fn resolve(&self, path: &str) -> Result<PathBuf> {
+        let path = Path::new(path);
+        if !path.is_absolute() {
+            bail!("`{}` is not an absolute path", path.display());
+        }
+        // Follows symlinks and `..`, so that they can't lead out of the roots
+        let path = std::fs::canonicalize(path)?;
+        let inside = |dir: &Path| std::fs::canonicalize(dir).is_ok_and(|dir| path.starts_with(dir));
+
+        if !self.roots.iter().any(|root| inside(root)) {
+            bail!(
+                "Access to `{}` is not allowed. Call `list_directory` with an empty path to get the allowed directories",
+                path.display()
+            );
+        }
+        let protected = [
+            eframe::storage_dir(crate::TITLE),
+            crate::config::config_dir(),
+        ];
+        if protected.iter().flatten().any(|dir| inside(dir)) {
+            bail!("Access to `{}` is not allowed", path.display());
+        }
+        Ok(path)
+    }
+}
+
+pub struct ToolRegistry {
+    tools: Vec<Arc<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    fn builtin() -> Self {
+        Self {
+            tools: vec![
+                Arc::new(CurrentTime),
+                Arc::new(ListDirectory),
+                Arc::new(ReadTextFile),
+            ],
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Arc<dyn Tool>> {
+        self.tools.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Arc<dyn Tool>> {
+        self.tools.iter().find(|tool| tool.name() == name)
+    }
+
+    /// Builds the function declarations for the enabled tools.
+    pub fn declarations(&self, enabled: &[String]) -> Option<GeminiTool> {
+        let declarations: Vec<FunctionDeclaration> = self
+            .tools
+            .iter()
+            .filter(|tool| enabled.iter().any(|name| name == tool.name()))
+            .filter_map(|tool| {
+                serde_json::from_value(json!({
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "parameters": tool.parameters(),
+                }))
+                .map_err(|e| log::error!("invalid declaration for tool `{}`: {e}", tool.name()))
+                .ok()
+            })
+            .collect();
+
+        if declarations.is_empty() {
+            None
+        } else {
+            Some(GeminiTool::with_functions(declarations))
+        }
+    }
+
+    /// Whether the user has to allow the calls of tool `name`.
+    pub fn needs_confirmation(&self, name: &str) -> bool {
+        self.get(name).is_some_and(|tool| tool.needs_confirmation())
+    }
+
+    /// Runs a tool and wraps its output into an object suitable for a `FunctionResponse`.
+    pub async fn execute(&self, name: &str, args: Value, scope: &ToolScope) -> Value {
+        let Some(tool) = self.get(name) else {
+            log::warn!("model called unknown tool `{name}`");
+            return json!({ "error": format!("Unknown tool `{name}`") });
+        };
+
+        log::info!("executing tool `{name}` with args {args}");
+        match tool.execute(args, scope).await {
+            Ok(value @ Value::Object(_)) => value,
+            Ok(value) => json!({ "result": value }),
+            Err(e) => {
+                log::warn!("tool `{name}` failed: {e}");
+                json!({ "error": e.to_string() })
+            }
+        }
+    }
+}
+
+/// Builds one of Gemini's server-side tools (e.g. `code_execution`) from its name.
+pub fn server_tool(name: &str) -> Option<GeminiTool> {
+    let mut tool = serde_json::Map::new();
+    tool.insert(name.to_string(), json!({}));
+    serde_json::from_value(Value::Object(tool))
+        .map_err(|e| log::error!("failed to build `{name}` tool: {e}"))
+        .ok()
+}
+
+fn string_arg(args: &Value, key: &str) -> Result<String> {
+    args.get(key)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| anyhow!("Missing string argument `{key}`"))
+}
+
+struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "get_current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current local date and time of the user, including the UTC offset."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn execute<'a>(&'a self, _args: Value, _scope: &'a ToolScope) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let now = chrono::Local::now();
+            Ok(json!({
+                "datetime": now.to_rfc3339(),
+                "weekday": now.format("%A").to_string(),
+            }))
+        })
+    }
+}
+
+struct ListDirectory;
+
+impl Tool for ListDirectory {
+    fn name(&self) -> &'static str {
+        "list_directory"
+    }
+
+    fn description(&self) -> &'static str {
+        "Lists the files and subdirectories of a directory on the user's machine. \
+         Only the directories chosen by the user can be accessed, an empty path lists them."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": {
+                    "type": "string",
+                    "description": "Absolute path of the directory, or an empty string"
+                }
+            },
+            "required": ["path"]
+        })
+    }
+
+    fn needs_confirmation(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(&'a self, args: Value, scope: &'a ToolScope) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let path = string_arg(&args, "path")?;
+            if path.trim().is_empty() {
+                let roots: Vec<_> = scope
+                    .roots
+                    .iter()
+                    .map(|r| r.display().to_string())
+                    .collect();
+                return Ok(json!({ "allowed_directories": roots }));
+            }
+            let path = scope.resolve(&path)?;
+            let mut entries = tokio::fs::read_dir(&path).await?;
+            let mut files = Vec::new();
+            while let Some(entry) = entries.next_entry().await? {
+                let is_dir = entry.file_type().await.map(|t| t.is_dir()).unwrap_or(false);
+                let mut name = entry.file_name().to_string_lossy().into_owned();
+                if is_dir {
+                    name.push('/');
+                }
+                files.push(name);
+            }
+            files.sort();
+            Ok(json!({ "entries": files }))
+        })
+    }
+}
+
+struct ReadTextFile;
+
+impl Tool for ReadTextFile {
+    fn name(&self) -> &'static str {
+        "read_text_file"
+    }
+
+    fn description(&self) -> &'static str {
+        "Reads a UTF-8 text file from the user's machine, within the directories chosen by the user. \
+         Files larger than 256 KiB are truncated."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "path": { "type": "string", "description": "Absolute path of the file" }
+            },
+            "required": ["path"]
+        })
+    }
+
+    fn needs_confirmation(&self) -> bool {
+        true
+    }
+
+    fn execute<'a>(&'a self, args: Value, scope: &'a ToolScope) -> ToolFuture<'a> {
+        const MAX_SIZE: usize = 256 * 1024;
+
+        Box::pin(async move {
+            let path = scope.resolve(&string_arg(&args, "path")?)?;
+            let bytes = tokio::fs::read(&path).await?;
+            let truncated = bytes.len() > MAX_SIZE;
+            let content = String::from_utf8_lossy(&bytes[..bytes.len().min(MAX_SIZE)]);
+            Ok(json!({ "content": content, "truncated": truncated }))
+        })
+    }
+}
@@ -1,4 +1,8 @@
-use std::fmt;
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{LazyLock, Mutex, PoisonError},
+};
 
 use chrono::{Datelike, Local};
 use eframe::{
@@ -10,6 +14,11 @@ use gemini_rust::{Gemini, GeminiBuilder, GenerationConfig, Model, ThinkingConfig
 use reqwest;
 use serde::{Deserialize, Serialize};
 
+use crate::{
+    config::{Field, Overrides},
+    secrets::Secrets,
+};
+
 #[derive(Default, Clone, Deserialize, Serialize)]
 #[serde(default)]
 pub struct ModelPicker {
@@ -23,6 +32,8 @@ pub enum RequestInfoType {
     LoginGoogle,
     LogoutGoogle,
     SelectProject(String),
+    RefreshModels,
+    ImportPreset,
 }
 
 #[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -41,101 +52,96 @@ impl fmt::Display for AuthMethod {
     }
 }
 
-/// Represents the available Gemini models.
-#[derive(
-    Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, enum_iterator::Sequence,
-)]
-pub enum GeminiModel {
-    #[default]
-    #[serde(rename = "gemini-3-flash-preview")]
-    Gemini30Flash,
-    #[serde(rename = "gemini-3-pro-preview")]
-    Gemini30Pro,
-    #[serde(rename = "gemini-2.0-flash")]
-    Gemini20Flash,
-
-    #[serde(rename = "gemini-2.0-flash-lite")]
-    Gemini20FlashLite,
-
-    #[serde(rename = "gemini-2.5-pro")]
-    Gemini25Pro,
-
-    #[serde(rename = "gemini-2.5-flash")]
-    Gemini25Flash,
-
-    #[serde(rename = "gemini-1.5-flash")]
-    Gemini15Flash,
+/// A Gemini model ID, e.g. `gemini-2.5-pro`. The known models are listed in
+/// [`crate::models::catalogue`].
+///
+/// IDs are interned, so the model can be copied around like the enum it used to be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GeminiModel(&'static str);
 
-    #[serde(rename = "gemini-1.5-flash-8b")]
-    Gemini15Flash8b,
-
-    #[serde(rename = "gemini-2.5-flash-preview-05-20")]
-    Gemini25FlashPreview0520,
+impl Default for GeminiModel {
+    fn default() -> Self {
+        Self("gemini-3-flash-preview")
+    }
+}
 
-    #[serde(rename = "gemini-2.0-flash-thinking-exp-01-21")]
-    Gemini20FlashThinkingExp0121,
+impl GeminiModel {
+    pub fn new(id: &str) -> Self {
+        static IDS: LazyLock<Mutex<HashSet<&'static str>>> = LazyLock::new(Default::default);
 
-    #[serde(rename = "gemini-2.0-flash-thinking-exp-1219")]
-    Gemini20FlashThinkingExp1219,
+        let id = id.trim().trim_start_matches("models/");
+        let mut ids = IDS.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(&interned) = ids.get(id) {
+            return Self(interned);
+        }
+        // Only a handful of distinct model IDs ever exist, leaking them is fine
+        let interned: &'static str = Box::leak(id.to_string().into_boxed_str());
+        ids.insert(interned);
+        Self(interned)
+    }
 
-    #[serde(rename = "gemma-3-1b-it")]
-    Gemma31bIt,
+    #[inline]
+    pub const fn id(self) -> &'static str {
+        self.0
+    }
+}
 
-    #[serde(rename = "gemma-3-4b-it")]
-    Gemma34bIt,
+/// Serialized as an enum unit variant, which is what saved settings and chats contain
+/// from the time models were a fixed enum.
+impl Serialize for GeminiModel {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_unit_variant("GeminiModel", 0, self.0)
+    }
+}
 
-    #[serde(rename = "gemma-3-12b-it")]
-    Gemma312bIt,
+impl<'de> Deserialize<'de> for GeminiModel {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        /// The variant name of the legacy enum is the model ID.
+        struct VariantName(GeminiModel);
 
-    #[serde(rename = "gemma-3-27b-it")]
-    Gemma327bIt,
+        impl<'de> Deserialize<'de> for VariantName {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserializer.deserialize_identifier(ModelVisitor).map(Self)
+            }
+        }
 
-    #[serde(rename = "gemma-3n-e4b-it")]
-    Gemma3nE4bIt,
+        struct ModelVisitor;
 
-    #[serde(rename = "gemma-3n-e2b-it")]
-    Gemma3nE2bIt,
+        impl<'de> serde::de::Visitor<'de> for ModelVisitor {
+            type Value = GeminiModel;
 
-    // Models for paid quota
-    #[serde(rename = "gemini-1.5-pro")]
-    Gemini15Pro,
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a model ID")
+            }
 
-    #[serde(rename = "gemini-2.5-pro-preview-03-25")]
-    Gemini25ProPreview0325,
+            fn visit_str<E: serde::de::Error>(self, v: &str) -> Result<Self::Value, E> {
+                Ok(GeminiModel::new(v))
+            }
 
-    #[serde(rename = "gemini-2.5-pro-preview-05-06")]
-    Gemini25ProPreview0506,
+            fn visit_enum<A: serde::de::EnumAccess<'de>>(
+                self,
+                data: A,
+            ) -> Result<Self::Value, A::Error> {
+                use serde::de::VariantAccess;
+                let (VariantName(model), variant) = data.variant()?;
+                variant.unit_variant()?;
+                Ok(model)
+            }
+        }
 
-    #[serde(rename = "gemini-2.5-pro-preview-06-05")]
-    Gemini25ProPreview0605,
-    // To add a new model, simply add a new variant here
-    // and its corresponding `rename` attribute.
-    // #[serde(rename = "new-model-name")]
-    // NewModelName,
+        deserializer.deserialize_enum("GeminiModel", &[], ModelVisitor)
+    }
 }
 
 impl From<GeminiModel> for Model {
     fn from(val: GeminiModel) -> Self {
-        let model_id = serde_json::to_value(val)
-            .expect("Failed to serialize model enum")
-            .as_str()
-            .expect("Model enum should serialize to string")
-            .to_string();
-
-        Model::Custom(format!("models/{}", model_id))
+        Model::Custom(format!("models/{}", val.0))
     }
 }
 
-/// Allows the enum to be printed or converted to its string representation.
 impl fmt::Display for GeminiModel {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            serde_json::to_string(self)
-                .expect("Failed to serialize model")
-                .trim_matches('"')
-        )
+        f.write_str(self.0)
     }
 }
 
@@ -182,52 +188,79 @@ fn collapsing_frame<R>(
     .response
 }
 
+/// HTTP client builder going through the configured proxy, if any.
+pub fn http_client(proxy_path: Option<String>) -> reqwest::ClientBuilder {
+    let mut client_builder = reqwest::Client::builder();
+
+    if let Some(proxy_url) = proxy_path {
+        if !proxy_url.is_empty() {
+            if let Ok(proxy) = reqwest::Proxy::all(proxy_url) {
+                client_builder = client_builder.proxy(proxy);
+            } else {
+                log::error!("Invalid proxy URL, ignoring it.");
+            }
+        }
+    }
+    client_builder
+}
+
 const TEMPLATE_HINT_TEXT: &str =
     "A system prompt for the model. E.g., 'You are a helpful assistant that specializes in writing Rust code.'";
 
 impl ModelPicker {
     pub fn create_client(
         &self,
-        api_key: &str,
-        proxy_path: Option<String>,
+        profile: &ApiKeyProfile,
     ) -> Result<Gemini, gemini_rust::ClientError> {
-        let mut client_builder = reqwest::Client::builder();
-
-        if let Some(proxy_url) = proxy_path {
-            if !proxy_url.is_empty() {
-                if let Ok(proxy) = reqwest::Proxy::all(proxy_url) {
-                    client_builder = client_builder.proxy(proxy);
-                } else {
-                    log::error!("Invalid proxy URL, ignoring it.");
-                }
+        let mut builder = GeminiBuilder::new(&profile.key)
+            .with_model(Model::from(self.selected))
+            .with_http_client(http_client(profile.proxy_path.clone()));
+        if let Some(base_url) = profile.base_url.as_deref().filter(|u| !u.is_empty()) {
+            match url::Url::parse(base_url) {
+                Ok(url) => builder = builder.with_base_url(url),
+                Err(e) => log::error!("Invalid API base URL, ignoring it: {e}"),
             }
         }
-
-        GeminiBuilder::new(api_key)
-            .with_model(Model::from(self.selected))
-            .with_http_client(client_builder)
-            .build()
+        builder.build()
     }
 
-    pub fn show<R>(&mut self, ui: &mut egui::Ui, _request_info: &mut R)
+    /// `model_source` is where the model comes from if it's managed, it can't be changed then.
+    pub fn show<R>(&mut self, ui: &mut egui::Ui, model_source: Option<&str>, _request_info: &mut R)
     where
         R: FnMut(RequestInfoType),
     {
-        egui::ComboBox::from_id_salt("model_selector_combobox")
-            .selected_text(self.selected.to_string())
-            .show_ui(ui, |ui| {
-                for model in enum_iterator::all::<GeminiModel>() {
-                    if ui
-                        .selectable_label(self.selected == model, model.to_string())
-                        .clicked()
-                    {
-                        self.selected = model;
-                    }
-                }
+        ui.horizontal(|ui| {
+            ui.add_enabled_ui(model_source.is_none(), |ui| {
+                egui::ComboBox::from_id_salt("model_selector_combobox")
+                    .selected_text(self.selected.to_string())
+                    .height(400.0)
+                    .show_ui(ui, |ui| {
+                        crate::models::show_grouped(ui, |ui, model| {
+                            let resp =
+                                ui.selectable_label(self.selected.id() == model.id, model.label());
+                            if resp.clicked() {
+                                self.selected = model.model();
+                            }
+                            resp
+                        });
+                    });
             });
+            lock_icon(ui, model_source);
+        });
+        let (known, capabilities) = {
+            let catalogue = crate::models::catalogue();
+            let known = catalogue.get(self.selected).is_some();
+            (known, catalogue.capabilities(self.selected))
+        };
+        if known {
+            ui.weak(capabilities.summary());
+        } else {
+            ui.weak(format!("⚠ {} is not in the model list", self.selected))
+                .on_hover_text("It may have been removed. Pick another model if requests fail");
+        }
 
         ui.collapsing("Inference Settings", |ui| {
-            self.settings.show(ui);
+            self.settings.show(ui, &capabilities);
         });
 
         collapsing_frame(ui, "System Prompt", |ui| {
@@ -258,9 +291,80 @@ impl ModelPicker {
         });
     }
 
-    #[inline]
+    /// Generation settings, limited to what the selected model supports.
     pub fn get_generation_config(&self) -> GenerationConfig {
-        self.settings.clone().into()
+        let mut config = self.settings.clone().into();
+        self.capabilities().restrict(&mut config);
+        config
+    }
+
+    #[inline]
+    pub fn capabilities(&self) -> crate::models::Capabilities {
+        crate::models::catalogue().capabilities(self.selected)
+    }
+
+    /// Returns the system prompt that should be sent to the model, if any.
+    pub fn system_instruction(&self) -> Option<String> {
+        self.system_prompt
+            .as_ref()
+            .filter(|prompt| !prompt.trim().is_empty())
+            .cloned()
+    }
+
+    /// Response format and schema configured for this chat.
+    #[inline]
+    pub fn structured_output(&self) -> &crate::structured::StructuredOutput {
+        &self.settings.structured
+    }
+
+    /// How the history is shortened once it gets too long.
+    #[inline]
+    pub const fn context_policy(&self) -> &crate::context::ContextPolicy {
+        &self.settings.context
+    }
+
+    /// Safety thresholds sent with every request of this chat.
+    pub fn safety_settings(&self) -> Vec<gemini_rust::SafetySetting> {
+        self.settings.safety.to_settings()
+    }
+
+    /// Explicit context caching settings of this chat.
+    #[inline]
+    pub const fn cache_policy(&self) -> &crate::cache::CachePolicy {
+        &self.settings.cache
+    }
+
+    /// Tools sent with every request of this chat.
+    pub fn tools(&self) -> Vec<gemini_rust::Tool> {
+        let mut tools = Vec::new();
+        if !self.capabilities().tools {
+            return tools;
+        }
+        if let Some(functions) = crate::tools::REGISTRY.declarations(&self.settings.tools) {
+            tools.push(functions);
+        }
+        if self.settings.code_execution {
+            tools.extend(crate::tools::server_tool("code_execution"));
+        }
+        if self.settings.google_search {
+            tools.extend(crate::tools::server_tool("google_search"));
+        }
+        if self.settings.url_context {
+            tools.extend(crate::tools::server_tool("url_context"));
+        }
+        tools
+    }
+
+    /// Directories the local tools of this chat may access.
+    pub fn tool_scope(&self) -> crate::tools::ToolScope {
+        crate::tools::ToolScope {
+            roots: self.settings.tool_roots.clone(),
+        }
+    }
+
+    /// Revokes the access of the file tools to every directory.
+    pub fn clear_tool_roots(&mut self) {
+        self.settings.tool_roots.clear();
     }
 }
 
@@ -271,9 +375,22 @@ struct ModelSettings {
     pub top_p: Option<f32>,
     pub top_k: Option<u32>,
     pub num_predict: Option<i32>, // Mapped to maxOutputTokens
+    pub candidate_count: Option<i32>,
     pub stop: Option<Vec<String>>,
     pub include_thoughts: bool,
     pub thinking_budget: Option<i32>,
+    pub tools: Vec<String>,
+    /// Directories the file tools may access. Nothing can be read until one is added.
+    pub tool_roots: Vec<std::path::PathBuf>,
+    #[serde(skip)]
+    pub new_tool_root: String,
+    pub code_execution: bool,
+    pub google_search: bool,
+    pub url_context: bool,
+    pub structured: crate::structured::StructuredOutput,
+    pub context: crate::context::ContextPolicy,
+    pub cache: crate::cache::CachePolicy,
+    pub safety: crate::safety::SafetySettings,
 }
 
 impl From<ModelSettings> for GenerationConfig {
@@ -283,7 +400,10 @@ impl From<ModelSettings> for GenerationConfig {
         config.top_p = value.top_p;
         config.top_k = value.top_k.map(|k| k as i32);
         config.max_output_tokens = value.num_predict;
+        config.candidate_count = value.candidate_count;
         config.stop_sequences = value.stop;
+        config.response_mime_type = value.structured.mime_type();
+        config.response_schema = value.structured.schema();
 
         if value.include_thoughts || value.thinking_budget.is_some() {
             let mut thinking_config = ThinkingConfig::default();
@@ -346,15 +466,23 @@ impl ModelSettings {
         });
     }
 
-    fn show(&mut self, ui: &mut egui::Ui) {
+    fn show(&mut self, ui: &mut egui::Ui, capabilities: &crate::models::Capabilities) {
         if ui.button("Reset Settings").clicked() {
             *self = Self::default();
         }
 
         collapsing_frame(ui, "Thinking", |ui| {
-            ui.label("Enable native thinking for Gemini 2.5 models to improve reasoning.");
+            if !capabilities.thinking {
+                ui.label("This model doesn't support thinking.");
+                return;
+            }
+            ui.label("Enable native thinking to improve reasoning.");
             ui.checkbox(&mut self.include_thoughts, "Include thought summaries");
 
+            let Some(range) = capabilities.thinking_budget else {
+                ui.weak("The thinking budget of this model can't be set.");
+                return;
+            };
             ui.add_enabled_ui(self.include_thoughts, |ui| {
                 let mut budget_enabled = self.thinking_budget.is_some();
                 ui.horizontal(|ui| {
@@ -369,24 +497,136 @@ impl ModelSettings {
                 }
 
                 if let Some(ref mut budget) = self.thinking_budget {
-                    ui.add(egui::DragValue::new(budget).speed(100.0).range(-1..=32768))
-                        .on_hover_text("Token budget for thinking. -1 for dynamic, 0 to disable.");
+                    let hover_text = if range.can_disable {
+                        format!(
+                            "Token budget for thinking, up to {}. -1 for dynamic, 0 to disable.",
+                            range.max
+                        )
+                    } else {
+                        format!(
+                            "Token budget for thinking, from {} to {}. -1 for dynamic.",
+                            range.min, range.max
+                        )
+                    };
+                    ui.add(
+                        egui::DragValue::new(budget)
+                            .speed(100.0)
+                            .range(-1..=range.max),
+                    )
+                    .on_hover_text(hover_text);
+                    *budget = range.clamp(*budget);
                 }
             });
         });
 
+        let output_limit = i32::try_from(capabilities.output_token_limit).unwrap_or(i32::MAX);
         Self::edit_numeric(ui, &mut self.temperature, 0.9, 0.01, 0.0..=1.0, "Temperature", "Controls the randomness of the output. Higher values (e.g., 1.0) produce more creative responses, while lower values (e.g., 0.2) make the output more deterministic.");
         Self::edit_numeric(
             ui,
             &mut self.num_predict,
-            2048,
+            2048.min(output_limit),
             1.0,
-            1..=8192,
+            1..=output_limit,
             "Max Output Tokens",
-            "Maximum number of tokens to generate in the response.",
+            "Maximum number of tokens to generate in the response. Responses cut off by this limit can be continued with the ⏩ Continue button.",
         );
         Self::edit_numeric(ui, &mut self.top_k, 40, 1.0, 1..=100, "Top-K", "Changes how the model selects tokens for output. A lower value limits the sampling to a smaller set of the most likely tokens.");
         Self::edit_numeric(ui, &mut self.top_p, 0.95, 0.01, 0.0..=1.0, "Top-P", "Changes how the model selects tokens for output, sampling from a cumulative probability distribution. Use either Top-K or Top-P, not both.");
+        Self::edit_numeric(ui, &mut self.candidate_count, 2, 0.05, 1..=8, "Candidate Count", "Number of alternative responses generated in one request. They are shown as tabs on the response, and the selected one is kept in the history.");
+
+        collapsing_frame(ui, "Structured Output", |ui| {
+            ui.label("Force the model to answer with JSON matching a schema, or with one of a fixed set of values. Responses are checked against the schema.");
+            self.structured.show(ui);
+        });
+
+        collapsing_frame(ui, "Context Window", |ui| {
+            self.context.show(ui, capabilities.input_token_limit);
+            ui.separator();
+            self.cache.show(ui);
+        });
+
+        collapsing_frame(ui, "Safety", |ui| {
+            ui.label("How strictly responses are filtered in each category. Blocked responses are marked in the chat.");
+            self.safety.show(ui);
+        });
+
+        collapsing_frame(ui, "Tools", |ui| {
+            if !capabilities.tools {
+                ui.label("This model doesn't support tools.");
+                return;
+            }
+            ui.horizontal(|ui| {
+                ui.add(toggle(&mut self.code_execution));
+                help(ui, "Let the model write and run Python code on Google's servers. The code and its output are shown in the chat", |ui| {
+                    ui.label("Code execution");
+                });
+            });
+            ui.horizontal(|ui| {
+                ui.add(toggle(&mut self.google_search));
+                help(ui, "Ground responses in Google Search results. The sources are listed under the response", |ui| {
+                    ui.label("Google Search");
+                });
+            });
+            ui.horizontal(|ui| {
+                ui.add(toggle(&mut self.url_context));
+                help(
+                    ui,
+                    "Let the model fetch and read the web pages linked in your messages",
+                    |ui| {
+                        ui.label("URL context");
+                    },
+                );
+            });
+            ui.separator();
+
+            ui.label("Local functions the model is allowed to call. Results are sent back to the model automatically.");
+            for tool in crate::tools::REGISTRY.iter() {
+                let mut enabled = self.tools.iter().any(|name| name == tool.name());
+                if ui
+                    .checkbox(&mut enabled, tool.name())
+                    .on_hover_text(tool.description())
+                    .changed()
+                {
+                    if enabled {
+                        self.tools.push(tool.name().to_string());
+                    } else {
+                        self.tools.retain(|name| name != tool.name());
+                    }
+                }
+            }
+
+            ui.label(
+                "Directories the file tools may access. Each call has to be allowed in the chat.",
+            );
+            self.tool_roots.retain(|root| {
+                let mut keep = true;
+                ui.horizontal(|ui| {
+                    ui.label(root.display().to_string());
+                    if ui.button("❌").clicked() {
+                        keep = false;
+                    }
+                });
+                keep
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.new_tool_root).hint_text("Absolute path"),
+                );
+                let path = std::path::PathBuf::from(self.new_tool_root.trim());
+                if ui
+                    .add_enabled(
+                        path.is_absolute() && path.is_dir(),
+                        egui::Button::new("Add"),
+                    )
+                    .clicked()
+                {
+                    if !self.tool_roots.contains(&path) {
+                        self.tool_roots.push(path);
+                    }
+                    self.new_tool_root.clear();
+                }
+            });
+        });
 
         collapsing_frame(ui, "Stop Sequence", |ui| {
             ui.label("A set of up to 5 character sequences that will stop output generation.");
@@ -533,11 +773,11 @@ fn toggle_ui(ui: &mut egui::Ui, on: &mut bool) -> egui::Response {
 }
 
 #[inline]
-fn toggle(on: &mut bool) -> impl egui::Widget + '_ {
+pub fn toggle(on: &mut bool) -> impl egui::Widget + '_ {
     move |ui: &mut egui::Ui| toggle_ui(ui, on)
 }
 
-fn help(ui: &mut egui::Ui, text: &str, add_contents: impl FnOnce(&mut egui::Ui)) {
+pub fn help(ui: &mut egui::Ui, text: &str, add_contents: impl FnOnce(&mut egui::Ui)) {
     ui.horizontal(|ui| {
         add_contents(ui);
         ui.add_enabled(false, egui::Label::new("(?)").selectable(false))
@@ -545,27 +785,187 @@ fn help(ui: &mut egui::Ui, text: &str, add_contents: impl FnOnce(&mut egui::Ui))
     });
 }
 
+/// Name of something looked up by name, e.g. `Key 2`, with a number appended
+/// until `is_taken` returns `false`.
+pub fn unique_name(base: &str, first: usize, is_taken: impl Fn(&str) -> bool) -> String {
+    (first..)
+        .map(|n| format!("{base} {n}"))
+        .find(|name| !is_taken(name))
+        .unwrap_or_default()
+}
+
+/// Text field renaming something looked up by name. The edit is kept while it is
+/// empty or taken by another item, but only applied to `name` once it is valid.
+/// Returns `true` if `name` changed.
+pub fn name_edit(
+    ui: &mut egui::Ui,
+    name: &mut String,
+    width: f32,
+    is_taken: impl Fn(&str) -> bool,
+) -> bool {
+    let id = ui.id().with("name_edit");
+    let mut draft: String = ui
+        .data_mut(|d| d.get_temp(id))
+        .unwrap_or_else(|| name.clone());
+    let response = ui.add(
+        egui::TextEdit::singleline(&mut draft)
+            .hint_text("Name")
+            .desired_width(width),
+    );
+
+    let trimmed = draft.trim();
+    let problem = if trimmed.is_empty() {
+        Some("The name can't be empty")
+    } else if trimmed != name.as_str() && is_taken(trimmed) {
+        Some("This name is already used")
+    } else {
+        None
+    };
+    let changed = problem.is_none() && trimmed != name.as_str();
+    if changed {
+        *name = trimmed.to_string();
+    }
+    if let Some(problem) = problem {
+        ui.colored_label(ui.visuals().error_fg_color, "⚠")
+            .on_hover_text(problem);
+    }
+
+    if response.has_focus() {
+        ui.data_mut(|d| d.insert_temp(id, draft));
+    } else {
+        ui.data_mut(|d| d.remove::<String>(id));
+    }
+    changed
+}
+
+/// Lock shown next to a setting managed by the environment or the config file.
+fn lock_icon(ui: &mut egui::Ui, source: Option<&str>) {
+    if let Some(source) = source {
+        ui.label("🔒")
+            .on_hover_text(format!("Set by {source}, it can't be changed here"));
+    }
+}
+
+/// How failed requests are retried on quota and transient server errors.
+#[derive(Debug, Clone, Copy, Deserialize, Serialize)]
+#[serde(default)]
+pub struct RetryPolicy {
+    pub enabled: bool,
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry in seconds, doubled after each attempt.
+    pub base_delay: f32,
+    pub max_delay: f32,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            max_attempts: 3,
+            base_delay: 2.0,
+            max_delay: 60.0,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Exponential backoff delay after the given (1-based) failed attempt.
+    pub fn backoff(&self, attempt: u32) -> std::time::Duration {
+        let delay = self.base_delay * 2f32.powi(attempt.saturating_sub(1) as i32);
+        std::time::Duration::from_secs_f32(delay.min(self.max_delay).max(0.0))
+    }
+
+    fn show(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.add(toggle(&mut self.enabled));
+            help(ui, "Automatically retry requests that failed because of rate limits (RESOURCE_EXHAUSTED) or temporary server errors. The delay suggested by the server is used when available", |ui| {
+                ui.label("Retry failed requests");
+            });
+        });
+        ui.add_enabled_ui(self.enabled, |ui| {
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.max_attempts).range(2..=10));
+                ui.label("attempts");
+            });
+            ui.horizontal(|ui| {
+                ui.add(
+                    egui::DragValue::new(&mut self.base_delay)
+                        .speed(0.1)
+                        .range(0.5..=60.0)
+                        .suffix(" s"),
+                );
+                ui.label("initial delay, up to");
+                ui.add(
+                    egui::DragValue::new(&mut self.max_delay)
+                        .speed(1.0)
+                        .range(1.0..=600.0)
+                        .suffix(" s"),
+                );
+            });
+        });
+    }
+}
+
+/// A named API key, e.g. a free-tier or a billed one.
+#[derive(Default, Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct ApiKeyProfile {
+    pub name: String,
+    /// Kept in the secrets file, see `Settings::secrets`.
+    #[serde(skip_serializing)]
+    pub key: String,
+    /// Proxy used with this key instead of the global one.
+    pub proxy_path: Option<String>,
+    /// Copied from `Settings::base_url`.
+    #[serde(skip)]
+    pub base_url: Option<String>,
+}
+
+impl ApiKeyProfile {
+    /// Name of the profile holding `Settings::api_key`.
+    pub const DEFAULT: &'static str = "Default";
+}
+
 // This is the main settings struct.
 #[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Settings {
     pub auth_method: AuthMethod,
+    /// Secrets are read from older app states and imported settings, but only saved
+    /// through `Settings::secrets`.
+    #[serde(skip_serializing)]
     pub api_key: String,
+    /// Named keys besides the default one.
+    pub api_keys: Vec<ApiKeyProfile>,
+    /// Move to the next key when a request fails with RESOURCE_EXHAUSTED.
+    pub rotate_keys: bool,
+    #[serde(skip_serializing)]
     pub oauth_token: String,
     pub project_id: String,
     #[serde(skip)]
     pub available_projects: Vec<String>,
 
     pub model_picker: ModelPicker,
+    pub presets: crate::presets::Presets,
     pub inherit_chat_picker: bool,
     pub use_streaming: bool,
     #[serde(default)]
     pub include_thoughts_in_history: bool,
     #[serde(default)]
     pub public_file_upload: bool,
+    pub retry: RetryPolicy,
+    /// How many chats may generate at once, `None` for no limit.
+    pub max_concurrent_generations: Option<usize>,
+    pub prompt_library: crate::prompts::PromptLibrary,
     pub proxy_path: Option<String>,
+    /// Endpoint of the Gemini API, e.g. a gateway. The default one if `None`.
+    pub base_url: Option<String>,
     pub let_it_snow: bool,
     is_winter: bool,
+    /// Values set by environment variables and `config.toml`.
+    #[serde(skip)]
+    pub overrides: Overrides,
 }
 
 impl Default for Settings {
@@ -573,23 +973,172 @@ impl Default for Settings {
         let is_winter = matches!(Local::now().month(), 12 | 1 | 2);
         Self {
             auth_method: AuthMethod::ApiKey,
-            api_key: String::new(), // todo try read from env
+            api_key: String::new(),
+            api_keys: Vec::new(),
+            rotate_keys: false,
             oauth_token: String::new(),
             project_id: String::new(),
             available_projects: Vec::new(),
             model_picker: ModelPicker::default(),
+            presets: crate::presets::Presets::default(),
             inherit_chat_picker: true,
             use_streaming: true,
             include_thoughts_in_history: false,
             public_file_upload: true,
+            retry: RetryPolicy::default(),
+            max_concurrent_generations: None,
+            prompt_library: crate::prompts::PromptLibrary::default(),
             proxy_path: None,
+            base_url: None,
             is_winter: is_winter,
             let_it_snow: is_winter,
+            overrides: Overrides::default(),
         }
     }
 }
 
 impl Settings {
+    /// The API keys and tokens, saved apart from the settings.
+    /// A key set by the environment or the config file is left out.
+    pub fn secrets(&self) -> Secrets {
+        Secrets {
+            api_key: if self.overrides.is_locked(Field::ApiKey) {
+                String::new()
+            } else {
+                self.api_key.clone()
+            },
+            api_keys: self
+                .api_keys
+                .iter()
+                .map(|p| (p.name.clone(), p.key.clone()))
+                .collect(),
+            oauth_token: self.oauth_token.clone(),
+        }
+    }
+
+    /// Masks every key and token in the logs, a managed key included.
+    /// Called whenever a secret is loaded, edited, added or removed.
+    pub fn hide_secrets_in_logs(&self) {
+        let secrets = self.secrets();
+        crate::logger::set_secrets(secrets.values().chain([self.api_key.as_str()]));
+    }
+
+    /// Fills in the secrets that are not set, e.g. after the settings were loaded.
+    pub fn restore_secrets(&mut self, mut secrets: Secrets) {
+        if self.api_key.is_empty() {
+            self.api_key = secrets.api_key;
+        }
+        if self.oauth_token.is_empty() {
+            self.oauth_token = secrets.oauth_token;
+        }
+        for profile in self.api_keys.iter_mut().filter(|p| p.key.is_empty()) {
+            if let Some(i) = secrets
+                .api_keys
+                .iter()
+                .position(|(n, _)| *n == profile.name)
+            {
+                profile.key = secrets.api_keys.remove(i).1;
+            }
+        }
+    }
+
+    /// Every API key that is set, the default one first, with the proxy it is used with.
+    pub fn key_profiles(&self) -> Vec<ApiKeyProfile> {
+        let default = ApiKeyProfile {
+            name: ApiKeyProfile::DEFAULT.to_string(),
+            key: self.api_key.clone(),
+            proxy_path: self.proxy_path.clone(),
+            base_url: self.base_url.clone(),
+        };
+        std::iter::once(default)
+            .chain(self.api_keys.iter().map(|profile| ApiKeyProfile {
+                proxy_path: profile.proxy_path.clone().or(self.proxy_path.clone()),
+                base_url: self.base_url.clone(),
+                ..profile.clone()
+            }))
+            .filter(|profile| !profile.key.is_empty())
+            .collect()
+    }
+
+    /// The key a chat uses, falling back to the first one if `name` is gone.
+    pub fn key_profile(&self, name: Option<&str>) -> Option<ApiKeyProfile> {
+        let mut profiles = self.key_profiles();
+        let idx = name
+            .and_then(|name| profiles.iter().position(|p| p.name == name))
+            .unwrap_or(0);
+        (idx < profiles.len()).then(|| profiles.swap_remove(idx))
+    }
+
+    /// Names of every key, to pick one in a chat.
+    pub fn key_names(&self) -> Vec<&str> {
+        std::iter::once(ApiKeyProfile::DEFAULT)
+            .chain(self.api_keys.iter().map(|p| p.name.as_str()))
+            .collect()
+    }
+
+    fn show_api_keys(&mut self, ui: &mut egui::Ui) {
+        let mut remove = None;
+        let mut keys_changed = false;
+        let names: Vec<String> = self.api_keys.iter().map(|p| p.name.clone()).collect();
+        for (i, profile) in self.api_keys.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                ui.horizontal(|ui| {
+                    // Secrets, chats and the rotation find keys by name
+                    name_edit(ui, &mut profile.name, 100.0, |name| {
+                        name == ApiKeyProfile::DEFAULT
+                            || names.iter().enumerate().any(|(j, n)| j != i && n == name)
+                    });
+                    keys_changed |= ui
+                        .add(
+                            egui::TextEdit::singleline(&mut profile.key)
+                                .password(true)
+                                .hint_text("API Key"),
+                        )
+                        .changed();
+                    if ui.button("🗑").on_hover_text("Remove key").clicked() {
+                        remove = Some(i);
+                    }
+                });
+                let mut use_proxy = profile.proxy_path.is_some();
+                ui.horizontal(|ui| {
+                    ui.checkbox(&mut use_proxy, "Own proxy");
+                    if !use_proxy {
+                        profile.proxy_path = None;
+                    } else {
+                        let proxy = profile.proxy_path.get_or_insert_default();
+                        ui.add(
+                            egui::TextEdit::singleline(proxy)
+                                .hint_text("http://your_proxy_address:port"),
+                        );
+                    }
+                });
+            });
+        }
+        if let Some(i) = remove {
+            self.api_keys.remove(i);
+            keys_changed = true;
+        }
+        if ui.button("➕ Add key").clicked() {
+            let name = unique_name("Key", self.api_keys.len() + 2, |name| {
+                self.api_keys.iter().any(|p| p.name == name)
+            });
+            self.api_keys.push(ApiKeyProfile {
+                name,
+                ..Default::default()
+            });
+            keys_changed = true;
+        }
+        if keys_changed {
+            self.hide_secrets_in_logs();
+        }
+        ui.horizontal(|ui| {
+            ui.add(toggle(&mut self.rotate_keys));
+            help(ui, "When a request fails because the quota of its key ran out (RESOURCE_EXHAUSTED), send it again with the next key. The chat keeps using the new key", |ui| {
+                ui.label("Switch keys when the quota runs out");
+            });
+        });
+    }
+
     pub fn show_modal(&mut self, modal: &Modal) {
         modal.show(|ui| {
             modal.title(ui, "Reset Settings");
@@ -606,14 +1155,17 @@ impl Settings {
                     modal.close();
                 }
                 if modal.caution_button(ui, "Yes").clicked() {
+                    let overrides = std::mem::take(&mut self.overrides);
                     *self = Self::default();
+                    overrides.apply(self);
+                    self.hide_secrets_in_logs();
                     modal.close();
                 }
             });
         });
     }
 
-    async fn ask_save_settings(settings: Self) {
+    async fn ask_save_settings(settings: Self, include_secrets: bool) {
         let Some(file) = rfd::AsyncFileDialog::new()
             .add_filter("JSON file", &["json"])
             .save_file()
@@ -629,7 +1181,22 @@ impl Settings {
             return;
         };
 
-        let _ = serde_json::to_writer_pretty(f, &settings)
+        let Ok(mut value) = serde_json::to_value(&settings)
+            .map_err(|e| log::error!("failed to save settings: {e}"))
+        else {
+            return;
+        };
+        if include_secrets {
+            let secrets = settings.secrets();
+            value["api_key"] = secrets.api_key.into();
+            value["oauth_token"] = secrets.oauth_token.into();
+            let profiles = value["api_keys"].as_array_mut().into_iter().flatten();
+            for (json, (_, key)) in profiles.zip(secrets.api_keys) {
+                json["key"] = key.into();
+            }
+        }
+
+        let _ = serde_json::to_writer_pretty(f, &value)
             .map_err(|e| log::error!("failed to save settings: {e}"));
     }
 
@@ -659,13 +1226,29 @@ impl Settings {
                     .min_row_height(32.0)
                     .show(ui, |ui| {
                         ui.label("API Key");
-                        ui.add(
-                            egui::TextEdit::singleline(&mut self.api_key)
-                                .password(true)
-                                .hint_text("Enter your Google AI Studio API Key"),
-                        );
+                        let source = self.overrides.source(Field::ApiKey);
+                        ui.horizontal(|ui| {
+                            if ui
+                                .add_enabled(
+                                    source.is_none(),
+                                    egui::TextEdit::singleline(&mut self.api_key)
+                                        .password(true)
+                                        .hint_text("Enter your Google AI Studio API Key"),
+                                )
+                                .changed()
+                            {
+                                self.hide_secrets_in_logs();
+                            }
+                            lock_icon(ui, source);
+                        });
                         ui.end_row();
                     });
+                ui.collapsing("More keys", |ui| {
+                    ui.label(
+                        "Chats use the default key unless another one is picked in their settings.",
+                    );
+                    self.show_api_keys(ui);
+                });
             }
             AuthMethod::CodeAssist => {
                 ui.horizontal(|ui| {
@@ -715,39 +1298,89 @@ impl Settings {
             });
         });
         ui.add_space(2.0);
-        self.model_picker.show(ui, request_info);
+        self.presets.show_bar(ui, &mut self.model_picker);
+        // Presets carry their own model, the managed one still wins
+        if let Some(model) = self.overrides.model() {
+            self.model_picker.selected = model;
+        }
+        let source = self.overrides.source(Field::Model);
+        self.model_picker.show(ui, source, request_info);
+        ui.add_space(4.0);
+        if crate::models::show_settings(ui) {
+            request_info(RequestInfoType::RefreshModels);
+        }
+
+        ui.separator();
+        ui.heading("Presets");
+        ui.label("Saved models, settings and system prompts. Save one from the model settings of a chat, and share it as a JSON file.");
+        if self.presets.show(ui) {
+            request_info(RequestInfoType::ImportPreset);
+        }
 
         ui.separator();
         ui.heading("Behavior");
         ui.horizontal(|ui| {
-            ui.add(toggle(&mut self.use_streaming));
+            let source = self.overrides.source(Field::UseStreaming);
+            ui.add_enabled(source.is_none(), toggle(&mut self.use_streaming));
             help(ui, "Receive the response as it's being generated. Disabling this will wait for the full response before displaying it", |ui| {
                 ui.label("Stream response");
             });
+            lock_icon(ui, source);
         });
         ui.horizontal(|ui| {
             ui.add(toggle(&mut self.include_thoughts_in_history));
-            help(ui, "When enabled, the model's 'thought' parts are appended to the session context for subsequent requests. Warning: This will rapidly increase token consumption", |ui| {
+            help(ui, "When enabled, the model's 'thought' parts are sent back in the session context for subsequent requests. Thought signatures are always sent back. Warning: This will rapidly increase token consumption", |ui| {
                 ui.label("Persist Thoughts in Context");
             });
         });
         ui.horizontal(|ui| {
-            ui.add(toggle(&mut self.public_file_upload));
+            let source = self.overrides.source(Field::PublicFileUpload);
+            ui.add_enabled(source.is_none(), toggle(&mut self.public_file_upload));
             help(ui, "When enabled, files will be uploaded to Google's servers (File API) instead of being sent as base64 inline data. Uploaded files are temporary and only accessible by you.", |ui| {
                 ui.label("Upload files (File API)");
             });
+            lock_icon(ui, source);
+        });
+        self.retry.show(ui);
+
+        let source = self.overrides.source(Field::MaxConcurrentGenerations);
+        let mut limit_enabled = self.max_concurrent_generations.is_some();
+        ui.horizontal(|ui| {
+            ui.add_enabled(source.is_none(), toggle(&mut limit_enabled));
+            help(ui, "Limit how many chats may generate a response at the same time. Other requests wait in a queue and start in order", |ui| {
+                ui.label("Limit parallel generations");
+            });
+            lock_icon(ui, source);
         });
+        if !limit_enabled {
+            self.max_concurrent_generations = None;
+        } else if self.max_concurrent_generations.is_none() {
+            self.max_concurrent_generations = Some(2);
+        }
+        if let Some(ref mut limit) = self.max_concurrent_generations {
+            ui.horizontal(|ui| {
+                ui.add_enabled(source.is_none(), egui::DragValue::new(limit).range(1..=16));
+                ui.label("chats at once");
+            });
+        }
+
+        ui.separator();
+        ui.heading("Prompt Library");
+        ui.label("Type /name in the chatbox to insert a template. {{variable}} placeholders are asked for when it is used.");
+        self.prompt_library.show(ui);
 
         ui.separator();
 
         ui.heading("Miscellaneous");
 
+        let source = self.overrides.source(Field::Proxy);
         let mut enabled = self.proxy_path.is_some();
         ui.horizontal(|ui| {
-            ui.add(toggle(&mut enabled));
+            ui.add_enabled(source.is_none(), toggle(&mut enabled));
             help(ui, "Use the proxy for gemini api request", |ui| {
                 ui.label("Use proxy");
             });
+            lock_icon(ui, source);
         });
         if !enabled {
             self.proxy_path = None;
@@ -756,11 +1389,38 @@ impl Settings {
         }
 
         if let Some(ref mut template) = self.proxy_path {
-            ui.add(
+            ui.add_enabled(
+                source.is_none(),
                 egui::TextEdit::singleline(template).hint_text("http://your_proxy_address:port"),
             );
         }
 
+        let source = self.overrides.source(Field::BaseUrl);
+        let mut enabled = self.base_url.is_some();
+        ui.horizontal(|ui| {
+            ui.add_enabled(source.is_none(), toggle(&mut enabled));
+            help(
+                ui,
+                "Send Gemini API requests to another endpoint, e.g. a gateway",
+                |ui| {
+                    ui.label("Custom API endpoint");
+                },
+            );
+            lock_icon(ui, source);
+        });
+        if !enabled {
+            self.base_url = None;
+        } else if self.base_url.is_none() {
+            self.base_url = Some(String::from(crate::models::DEFAULT_BASE_URL));
+        }
+
+        if let Some(ref mut base_url) = self.base_url {
+            ui.add_enabled(
+                source.is_none(),
+                egui::TextEdit::singleline(base_url).hint_text(crate::models::DEFAULT_BASE_URL),
+            );
+        }
+
         // ui.toggle_value(&mut self.let_it_snow, "Let It Snow!");
         if ui.add(egui::Button::new("Let It Snow!").selected(self.let_it_snow)).clicked() {
             self.let_it_snow = !self.let_it_snow;
@@ -785,17 +1445,22 @@ impl Settings {
         }
 
         ui.label("Save and load settings as JSON");
+        let id = ui.id().with("export_secrets");
+        let mut include_secrets = ui.data_mut(|d| d.get_temp(id)).unwrap_or(false);
         ui.horizontal(|ui| {
             if ui.button("Save").clicked() {
                 let settings = self.clone();
                 tokio::spawn(async move {
-                    Self::ask_save_settings(settings).await;
+                    Self::ask_save_settings(settings, include_secrets).await;
                 });
             }
             if ui.button("Load").clicked() {
                 request_info(RequestInfoType::LoadSettings);
             }
+            ui.checkbox(&mut include_secrets, "Include API keys and tokens")
+                .on_hover_text("Anyone with the file can use your keys and account");
         });
+        ui.data_mut(|d| d.insert_temp(id, include_secrets));
     }
 }
 